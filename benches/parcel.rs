@@ -0,0 +1,85 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Time decoding parcels of growing size. The time per MiB stays the same
+//! since reads don't move the remaining data.
+
+extern crate android_binder;
+
+use android_binder::service::Parcel;
+use std::time::{Duration, Instant};
+
+const MIB: usize = 1024 * 1024;
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_nanos()) / 1_000_000.0
+}
+
+fn report(name: &str, size: usize, elapsed: Duration) {
+    let ms = millis(elapsed);
+    println!("{:>6} {:>3} MiB: {:>8.2} ms, {:>6.2} ms/MiB", name, size / MIB, ms, ms * MIB as f64 / size as f64);
+}
+
+fn bench_i32(size: usize) {
+    let mut parcel = Parcel::default();
+    for n in 0..size / 4 {
+        parcel.put_i32(n as i32).unwrap();
+    }
+    let mut parcel = Parcel::from_buf(&parcel);
+
+    let start = Instant::now();
+    while parcel.data_avail() > 0 {
+        parcel.get_i32().unwrap();
+    }
+    report("i32", size, start.elapsed());
+}
+
+fn bench_i32_vec(size: usize) {
+    let values: Vec<i32> = (0..(size / 4 - 1) as i32).collect();
+    let mut parcel = Parcel::default();
+    parcel.put_vec(&values).unwrap();
+    let mut parcel = Parcel::from_buf(&parcel);
+
+    let start = Instant::now();
+    parcel.get_vec::<i32>().unwrap();
+    report("i32[]", size, start.elapsed());
+}
+
+fn bench_str16(size: usize) {
+    let s = "android.os.IServiceManager";
+    let mut parcel = Parcel::default();
+    while parcel.data_size() < size {
+        parcel.put_str16(s).unwrap();
+    }
+    let mut parcel = Parcel::from_buf(&parcel);
+
+    let start = Instant::now();
+    while parcel.data_avail() > 0 {
+        parcel.get_str16().unwrap();
+    }
+    report("str16", size, start.elapsed());
+}
+
+fn main() {
+    for &size in &[MIB, 4 * MIB, 16 * MIB, 64 * MIB] {
+        bench_i32(size);
+    }
+    for &size in &[MIB, 4 * MIB, 16 * MIB, 64 * MIB] {
+        bench_i32_vec(size);
+    }
+    for &size in &[MIB, 4 * MIB, 16 * MIB, 64 * MIB] {
+        bench_str16(size);
+    }
+}
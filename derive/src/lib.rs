@@ -0,0 +1,219 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! `#[derive(Parcelable)]`, see `android_binder::service::Parcelable`.
+
+extern crate proc_macro;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use quote::Tokens;
+use syn::{Body, DeriveInput, Field, Ident, MetaItem, NestedMetaItem, Variant, VariantData};
+
+#[proc_macro_derive(Parcelable, attributes(parcelable))]
+pub fn derive_parcelable(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+    let body = match ast.body {
+        Body::Struct(ref data) => parcelable_struct(&ast, data),
+        Body::Enum(ref variants) if variants.iter().all(|v| v.data == VariantData::Unit) => {
+            parcelable_enum(&ast, variants)
+        }
+        Body::Enum(ref variants) => parcelable_union(&ast, variants),
+    };
+
+    let name = &ast.ident;
+    let mut generics = ast.generics.clone();
+    for param in &ast.generics.ty_params {
+        let bound = format!("where {}: ::android_binder::service::Parcelable", param.ident);
+        let bound = syn::parse_where_clause(&bound).unwrap();
+        generics.where_clause.predicates.extend(bound.predicates);
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let tokens = quote! {
+        impl #impl_generics ::android_binder::service::Parcelable for #name #ty_generics #where_clause {
+            #body
+        }
+    };
+    tokens.parse().unwrap()
+}
+
+/// Whether `field` is marked `#[parcelable(default)]`.
+fn has_default(field: &Field) -> bool {
+    field.attrs.iter().any(|a| match a.value {
+        MetaItem::List(ref ident, ref items) if ident == "parcelable" => items.iter().any(|i| match *i {
+            NestedMetaItem::MetaItem(MetaItem::Word(ref word)) => word == "default",
+            _ => false,
+        }),
+        _ => false,
+    })
+}
+
+/// The fields preceded by their size, like a stable AIDL parcelable.
+fn parcelable_struct(ast: &DeriveInput, data: &VariantData) -> Tokens {
+    let name = &ast.ident;
+    let fields = data.fields();
+    let required = fields.iter().take_while(|f| !has_default(f)).count();
+    if fields[required..].iter().any(|f| !has_default(f)) {
+        panic!("#[derive(Parcelable)] requires the fields of {} marked #[parcelable(default)] to be last", name);
+    }
+    let write = fields.iter().enumerate().map(|(n, f)| {
+        let field = f.ident.clone().unwrap_or_else(|| Ident::new(n));
+        quote! { parcel.put(&value.#field)?; }
+    });
+    let read = fields.iter().enumerate().map(|(n, f)| {
+        let field = f.ident.clone().unwrap_or_else(|| Ident::new(n));
+        if n < required {
+            quote! {
+                #field: parcel.get_sized_field(end)?
+                    .ok_or_else(|| ::android_binder::errors::Error::from(::android_binder::errors::StatusCode::NotEnoughData))?
+            }
+        } else {
+            quote! { #field: parcel.get_sized_field(end)?.unwrap_or_default() }
+        }
+    });
+
+    quote! {
+        fn write_to_parcel(&self, parcel: &mut ::android_binder::service::Parcel) -> ::android_binder::errors::Result<()> {
+            Self::write_nullable(Some(self), parcel)
+        }
+
+        fn read_from_parcel(parcel: &mut ::android_binder::service::Parcel) -> ::android_binder::errors::Result<Self> {
+            Self::read_nullable(parcel)?.ok_or_else(|| ::android_binder::errors::StatusCode::UnexpectedNull.into())
+        }
+
+        fn write_nullable(value: Option<&Self>, parcel: &mut ::android_binder::service::Parcel) -> ::android_binder::errors::Result<()> {
+            let value = match value {
+                Some(value) => value,
+                None => return parcel.put_i32(0),
+            };
+            parcel.put_i32(1)?;
+            parcel.put_sized(|parcel| {
+                #(#write)*
+                Ok(())
+            })
+        }
+
+        fn read_nullable(parcel: &mut ::android_binder::service::Parcel) -> ::android_binder::errors::Result<Option<Self>> {
+            if parcel.get_i32()? == 0 {
+                return Ok(None);
+            }
+            parcel.get_sized(|parcel, end| {
+                let _ = end;
+                Ok(Some(#name { #(#read),* }))
+            })
+        }
+    }
+}
+
+/// The discriminant as `i32`, or `i64` for `#[repr(i64)]`.
+fn parcelable_enum(ast: &DeriveInput, variants: &[Variant]) -> Tokens {
+    let name = &ast.ident;
+    let repr_i64 = ast.attrs.iter().any(|a| match a.value {
+        MetaItem::List(ref ident, ref items) if ident == "repr" => items.iter().any(|i| match *i {
+            NestedMetaItem::MetaItem(MetaItem::Word(ref ty)) => ty == "i64",
+            _ => false,
+        }),
+        _ => false,
+    });
+    let (ty, size, put, get) = if repr_i64 {
+        (quote!(i64), 8usize, quote!(put_i64), quote!(get_i64))
+    } else {
+        (quote!(i32), 4usize, quote!(put_i32), quote!(get_i32))
+    };
+    let write = variants.iter().map(|v| {
+        let variant = &v.ident;
+        quote! { #name::#variant => #name::#variant as #ty }
+    });
+    let read = variants.iter().map(|v| {
+        let variant = &v.ident;
+        quote! { d if d == #name::#variant as #ty => Ok(#name::#variant) }
+    });
+
+    quote! {
+        const MIN_SIZE: usize = #size;
+
+        fn write_to_parcel(&self, parcel: &mut ::android_binder::service::Parcel) -> ::android_binder::errors::Result<()> {
+            parcel.#put(match *self {
+                #(#write),*
+            })
+        }
+
+        fn read_from_parcel(parcel: &mut ::android_binder::service::Parcel) -> ::android_binder::errors::Result<Self> {
+            match parcel.#get()? {
+                #(#read,)*
+                _ => Err(::android_binder::errors::StatusCode::BadValue.into()),
+            }
+        }
+    }
+}
+
+/// The index of the variant and its value, like an AIDL union.
+fn parcelable_union(ast: &DeriveInput, variants: &[Variant]) -> Tokens {
+    let name = &ast.ident;
+    for v in variants {
+        match v.data {
+            VariantData::Tuple(ref fields) if fields.len() == 1 => {}
+            _ => panic!("#[derive(Parcelable)] requires variant {} to hold exactly one value", v.ident),
+        }
+    }
+    let write = variants.iter().enumerate().map(|(n, v)| {
+        let variant = &v.ident;
+        let tag = n as i32;
+        quote! {
+            #name::#variant(ref value) => {
+                parcel.put_i32(#tag)?;
+                parcel.put(value)
+            }
+        }
+    });
+    let read = variants.iter().enumerate().map(|(n, v)| {
+        let variant = &v.ident;
+        let tag = n as i32;
+        quote! { #tag => Ok(Some(#name::#variant(parcel.get()?))) }
+    });
+
+    quote! {
+        fn write_to_parcel(&self, parcel: &mut ::android_binder::service::Parcel) -> ::android_binder::errors::Result<()> {
+            Self::write_nullable(Some(self), parcel)
+        }
+
+        fn read_from_parcel(parcel: &mut ::android_binder::service::Parcel) -> ::android_binder::errors::Result<Self> {
+            Self::read_nullable(parcel)?.ok_or_else(|| ::android_binder::errors::StatusCode::UnexpectedNull.into())
+        }
+
+        fn write_nullable(value: Option<&Self>, parcel: &mut ::android_binder::service::Parcel) -> ::android_binder::errors::Result<()> {
+            let value = match value {
+                Some(value) => value,
+                None => return parcel.put_i32(0),
+            };
+            parcel.put_i32(1)?;
+            match *value {
+                #(#write)*
+            }
+        }
+
+        fn read_nullable(parcel: &mut ::android_binder::service::Parcel) -> ::android_binder::errors::Result<Option<Self>> {
+            if parcel.get_i32()? == 0 {
+                return Ok(None);
+            }
+            match parcel.get_i32()? {
+                #(#read,)*
+                _ => Err(::android_binder::errors::StatusCode::BadValue.into()),
+            }
+        }
+    }
+}
@@ -22,7 +22,8 @@ extern crate android_binder;
 
 use errors::*;
 use error_chain::ChainedError;
-use android_binder::service::{Parcel, ServiceManager};
+use android_binder::service::{LocalBinder, Parcel, ServiceManager, DUMP_FLAG_PRIORITY_DEFAULT};
+use std::sync::Arc;
 
 mod errors {
     error_chain! {
@@ -32,11 +33,21 @@ mod errors {
     }
 }
 
+struct TestService;
+
+impl LocalBinder for TestService {
+    fn on_transact(&self, code: u32, data: &mut Parcel, reply: &mut Parcel, _flags: u32) -> ::android_binder::errors::Result<()> {
+        info!("Received transaction {} with {} bytes", code, data.len());
+        reply.put_i32(0)
+    }
+}
+
 fn run() -> Result<i32> {
     let service_manager = ServiceManager::new()?;
 
-    info!("Adding blah service");
-    let service = service_manager.add_service("BinderTestService", false)?;
+    info!("Adding test service");
+    service_manager.add_service("BinderTestService", Arc::new(TestService), false, DUMP_FLAG_PRIORITY_DEFAULT)?;
+    service_manager.binder().serve()?;
     Ok(0)
 }
 
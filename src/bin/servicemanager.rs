@@ -0,0 +1,59 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! The context manager of a binder device, e.g one allocated in a binderfs
+//! mount.
+//!
+//! Usage: `servicemanager [--policy FILE] [DEVICE]`
+//!
+//! Without a policy every uid may add, find and list any service. See
+//! `Policy` for the file format.
+
+extern crate android_binder;
+extern crate error_chain;
+
+use android_binder::binder::{BinderBuilder, BINDER_DEVICE};
+use android_binder::errors::*;
+use android_binder::service::{Policy, ServiceManagerServer};
+use error_chain::ChainedError;
+use std::env;
+use std::process;
+use std::sync::Arc;
+
+fn run() -> Result<()> {
+    let mut device = BINDER_DEVICE.to_owned();
+    let mut policy = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--policy" {
+            let path = args.next().ok_or("Missing policy file")?;
+            policy = Some(Policy::load(path)?);
+        } else {
+            device = arg;
+        }
+    }
+
+    // Like the C implementation serve from a single thread.
+    let binder = Arc::new(BinderBuilder::new().device(&device).max_threads(0).build()?);
+    ServiceManagerServer::start_with_policy(&binder, policy)?;
+    binder.serve()
+}
+
+fn main() {
+    if let Err(ref e) = run() {
+        eprintln!("{}", e.display_chain());
+        process::exit(1);
+    }
+}
@@ -14,49 +14,51 @@
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use errors::*;
-use nix::fcntl::*;
-use nix::fcntl::OFlag;
-use nix::sys::mman::*;
-use nix::sys::stat::Mode;
-use nix::unistd::close;
-use std::mem::size_of;
-use std::os::unix::io::RawFd;
-use std::slice::from_raw_parts;
+use service::{LocalBinder, Parcel, RemoteBinder};
+use service::remote::Proxy;
+use nix::libc::geteuid;
+use std::collections::HashMap;
+use std::process;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use super::buffer::Buffer;
+use super::builder::BinderBuilder;
+use super::node::Nodes;
+use super::thread_state::ThreadState;
+use super::transport::Transport;
 use types::*;
 use utils::any_as_u8_slice;
 
-const DEVICE: &str = "/dev/binder";
-#[cfg(feature = "binder_version_7")]
-const BINDER_PROTOCOL_VERSION: i32 = 7;
-#[cfg(feature = "binder_version_8")]
-const BINDER_PROTOCOL_VERSION: i32 = 8;
-const BINDER_IOC_MAGIC: u8 = b'b';
-const READ_SIZE: usize = 32 * 4;
-const MAX_THREADS: usize = 15;
-const MAP_SIZE: usize = ((1 * 1024 * 1024) - (4096 * 2));
-
-ioctl!(readwrite binder_write_read with BINDER_IOC_MAGIC, 1; BinderWriteRead);
-ioctl!(write_ptr _binder_set_idle_timeout with BINDER_IOC_MAGIC, 3; i64);
-ioctl!(write_ptr binder_set_max_threads with BINDER_IOC_MAGIC, 5; usize);
-ioctl!(write_ptr _binder_set_idle_priotity with BINDER_IOC_MAGIC, 6; i32);
-ioctl!(write_int _binder_set_context_mgr with BINDER_IOC_MAGIC, 7);
-ioctl!(write_int binder_thread_exit with BINDER_IOC_MAGIC, 8);
-ioctl!(readwrite binder_version with BINDER_IOC_MAGIC, 9; BinderVersion);
+pub(crate) const MAX_THREADS: u32 = 15;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
 
 pub struct Binder {
-    fd: RawFd,
-    mapped: Vec<u8>,
+    /// Identifies the binder in the per thread states.
+    id: usize,
+    transport: Arc<dyn Transport>,
+    /// Selects the layout of the structures exchanged with the driver.
+    protocol: Protocol,
+    nodes: Nodes,
+    /// Receives the transactions to handle 0 if this is the context manager.
+    context_object: Mutex<Option<Arc<dyn LocalBinder>>>,
+    proxies: Mutex<HashMap<u32, Weak<Proxy>>>,
+    pool: Mutex<ThreadPool>,
 }
 
-#[repr(packed)]
-pub struct WriteBuffer {
-    command: BinderDriverCommandProtocol,
-    transaction: BinderTransactionData,
+/// Looper threads started on request of the driver.
+struct ThreadPool {
+    /// Set once the pool is started. Spawned threads keep the binder alive.
+    binder: Option<Weak<Binder>>,
+    max_threads: u32,
+    /// Number of threads spawned on request of the driver.
+    spawned: usize,
 }
 
 #[derive(Debug)]
 pub enum Reply {
-    Data(Vec<u8>),
+    Data(Parcel),
     StatusCode(u32),
 }
 
@@ -66,224 +68,512 @@ pub enum CallResult {
     Reply(Reply),
 }
 
-impl<'a> Binder {
+impl Binder {
+    /// Open the default binder device.
     pub fn new() -> Result<Binder> {
-        let mut flags = OFlag::empty();
-        flags.set(O_RDWR, true);
-        flags.set(O_CLOEXEC, true);
+        BinderBuilder::new().build()
+    }
+
+    /// Configure the device to open, see `BinderBuilder`.
+    pub fn builder() -> BinderBuilder {
+        BinderBuilder::new()
+    }
+
+    /// Use `transport` to talk to the driver, e.g a process of a `MockDriver`.
+    pub fn with_transport(transport: Box<dyn Transport>) -> Result<Binder> {
+        Binder::from_transport(transport, MAX_THREADS)
+    }
+
+    pub(crate) fn from_transport(transport: Box<dyn Transport>, max_threads: u32) -> Result<Binder> {
+        let protocol = Protocol::from_version(transport.version()?)?;
+        info!("Binder protocol version {}", protocol.version());
+
+        info!("Setting max threads to {}", max_threads);
+        transport.set_max_threads(max_threads)?;
+
+        Ok(Binder {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            transport: Arc::from(transport),
+            protocol,
+            nodes: Nodes::default(),
+            context_object: Mutex::new(None),
+            proxies: Mutex::new(HashMap::new()),
+            pool: Mutex::new(ThreadPool {
+                binder: None,
+                max_threads,
+                spawned: 0,
+            }),
+        })
+    }
 
-        let fd = open(DEVICE, flags, Mode::empty()).chain_err(|| {
-            format!("Failed to open {}", DEVICE)
+    /// Set the number of looper threads the driver may ask to spawn in
+    /// addition to the threads joining the pool on their own.
+    pub fn set_max_threads(&self, threads: u32) -> Result<()> {
+        info!("Setting max threads to {}", threads);
+        self.transport.set_max_threads(threads)?;
+        self.pool.lock().unwrap().max_threads = threads;
+        Ok(())
+    }
+
+    /// Register a local object to receive transactions. The returned cookie
+    /// identifies the object in transactions addressed to it.
+    pub fn register_object(&self, object: Arc<dyn LocalBinder>) -> BinderPtr {
+        self.nodes.register(object)
+    }
+
+    /// Become the context manager, i.e the target of handle 0, and dispatch
+    /// the transactions sent to it to `object`. Only one process of a binder
+    /// context can be the context manager.
+    pub fn become_context_manager(&self, object: Arc<dyn LocalBinder>) -> Result<()> {
+        *self.context_object.lock().unwrap() = Some(object);
+        self.transport.set_context_mgr().inspect_err(|_| {
+            self.context_object.lock().unwrap().take();
         })?;
+        info!("Became context manager");
+        Ok(())
+    }
 
-        if !unsafe {
-            let mut version_data = BinderVersion::default();
-            binder_version(fd, &mut version_data)
-                .chain_err(|| "Failed to get version")
-                .map(|_| version_data.protocol_version as i32)
-                .map(|d| d == BINDER_PROTOCOL_VERSION)?
-        }
-        {
-            return Err("Binder protocol version mismatch".into());
-        }
-        info!("Binder version check passed");
+    pub fn call(&self, parcel: &Parcel, target: u32, code: u32, flags: u32) -> Result<CallResult> {
+        debug!("Call transaction data:");
+        hex!(parcel);
 
-        let mut binder = Binder {
-            fd,
-            mapped: vec![0; MAP_SIZE],
-        };
+        let one_way = (flags & TransactionFlags::ONE_WAY as u32) != 0;
+        let command = BinderDriverCommandProtocol::BC_TRANSACTION;
+        self.send(command, parcel, target, code, flags, !one_way)
+    }
 
-        let mut prot_flags = ProtFlags::empty();
-        prot_flags.set(PROT_READ, true);
-        let mut flags = MapFlags::empty();
-        flags.set(MAP_PRIVATE, true);
-        let mapped = (&mut binder.mapped).as_mut_ptr() as *mut ::nix::libc::c_void;
-        unsafe {
-            mmap(mapped, MAP_SIZE, prot_flags, flags, fd, 0).chain_err(
-                || "Failed to mmap",
-            )?;
+    /// Start serving incoming transactions with a main looper thread and
+    /// the threads the driver asks for with `BR_SPAWN_LOOPER`, up to the
+    /// configured max threads. Does nothing if the pool already started.
+    pub fn start_thread_pool(binder: &Arc<Binder>) -> Result<()> {
+        {
+            let mut pool = binder.pool.lock().unwrap();
+            if pool.binder.is_some() {
+                return Ok(());
+            }
+            pool.binder = Some(Arc::downgrade(binder));
         }
-        info!("Mapped {} bytes", MAP_SIZE);
+        binder.spawn_looper(true)
+    }
 
-        info!("Setting max threads to {}", MAX_THREADS);
-        unsafe {
-            binder_set_max_threads(fd, &MAX_THREADS).chain_err(
-                || "Failed to set max threads",
-            )?;
-        };
+    /// Enter the looper on the calling thread and dispatch incoming
+    /// transactions to the registered objects. See `join_thread_pool`.
+    pub fn serve(&self) -> Result<()> {
+        self.join_thread_pool(true)
+    }
 
-        info!("Entering looper");
-        let mut data = vec![];
-        data.write_u32::<LittleEndian>(BinderDriverCommandProtocol::BC_ENTER_LOOPER as u32)
-            .unwrap();
-        let mut d = BinderWriteRead {
-            write_size: data.len() as BinderSize,
-            write_buffer: (&mut data).as_mut_ptr() as BinderPtr,
-            ..Default::default()
-        };
-        unsafe {
-            binder_write_read(fd, &mut d).chain_err(
-                || "Failed to enter looper",
-            )?;
+    /// Serve incoming transactions on the calling thread until an error
+    /// occurs. The `main` looper is entered with `BC_ENTER_LOOPER` while
+    /// threads spawned on request of the driver register with
+    /// `BC_REGISTER_LOOPER`. The thread leaves the looper with
+    /// `BC_EXIT_LOOPER` when returning.
+    pub fn join_thread_pool(&self, main: bool) -> Result<()> {
+        let command = if main {
+            BinderDriverCommandProtocol::BC_ENTER_LOOPER
+        } else {
+            BinderDriverCommandProtocol::BC_REGISTER_LOOPER
         };
+        info!("Entering looper with {:?}", command);
+        self.queue_command(command, &[]);
+        ThreadState::with(self.id, |t| t.looper = true);
 
-        Ok(binder)
-    }
-
-    pub fn call(&self, msg: &[u8], target: u32, code: u32, flags: u32) -> Result<CallResult> {
-        let write_buffer = WriteBuffer {
-            command: BinderDriverCommandProtocol::BC_TRANSACTION,
-            transaction: BinderTransactionData {
-                target,
-                cookie: 0,
-                code,
-                flags: flags,
-                sender_pid: 0,
-                sender_euid: 0,
-                data_size: msg.len() as BinderSize,
-                offsets_size: 0,
-                data: msg.as_ptr() as BinderPtr,
-            },
+        let result = loop {
+            let result = self.read_command().and_then(|(c, d)| {
+                info!("BinderDriverReturnProtocol is {:?}", c);
+                self.execute(c, &mut &d[..])
+            });
+            if let Err(e) = result {
+                break Err(e);
+            }
         };
 
-        let mut read_buffer: [u8; READ_SIZE] = [0; READ_SIZE];
-        let mut bwr = BinderWriteRead {
-            write_size: size_of::<WriteBuffer>() as BinderSize,
-            write_consumed: 0,
-            write_buffer: (&write_buffer as *const WriteBuffer) as BinderPtr,
-            read_size: size_of::<[u8; READ_SIZE]>() as BinderSize,
-            read_consumed: 0,
-            read_buffer: (&mut read_buffer as *mut [u8; READ_SIZE]) as BinderPtr,
+        info!("Leaving looper");
+        ThreadState::with(self.id, |t| t.looper = false);
+        self.queue_command(BinderDriverCommandProtocol::BC_EXIT_LOOPER, &[]);
+        self.flush().unwrap_or_else(|e| {
+            error!("Failed to exit looper: {}", e);
+        });
+        result
+    }
+
+    /// Spawn a looper thread if the pool is started.
+    fn spawn_looper(&self, main: bool) -> Result<()> {
+        let (binder, n) = {
+            let mut pool = self.pool.lock().unwrap();
+            let binder = match pool.binder.as_ref().and_then(|b| b.upgrade()) {
+                Some(binder) => binder,
+                None => {
+                    warn!("Ignoring looper request without started thread pool");
+                    return Ok(());
+                }
+            };
+            if !main {
+                if pool.spawned >= pool.max_threads as usize {
+                    warn!("Ignoring looper request exceeding {} threads", pool.max_threads);
+                    return Ok(());
+                }
+                pool.spawned += 1;
+            }
+            (binder, if main { 0 } else { pool.spawned })
         };
 
-        debug!("Call transaction data:");
-        hex!(msg);
+        thread::Builder::new()
+            .name(format!("binder_{}", n))
+            .spawn(move || {
+                if let Err(e) = binder.join_thread_pool(main) {
+                    error!("Looper {} failed: {}", n, e);
+                }
+                binder.transport.thread_exit().unwrap_or_else(|_| {
+                    error!("Failed to exit binder thread");
+                });
+                ThreadState::remove(binder.id);
+            })
+            .chain_err(|| "Failed to spawn looper thread")?;
+        Ok(())
+    }
 
+    /// Queue `out` and process the returned commands until either the
+    /// transaction is complete or, if `reply` is set, the reply arrived.
+    ///
+    /// Commands read after the response are processed on the next read of
+    /// this thread.
+    fn wait_for_response(&self, out: &[u8], reply: bool) -> Result<CallResult> {
         debug!("Writing:");
-        hex!(any_as_u8_slice(&bwr));
-
-        unsafe {
-            binder_write_read(self.fd, &mut bwr).chain_err(|| "Failed to write/read")?;
-        };
-        let mut d = &read_buffer[..(bwr.read_consumed as usize)];
-        debug!("Reply data:");
-        hex!(&d);
+        hex!(out);
+        ThreadState::with(self.id, |t| t.output.extend_from_slice(out));
 
         loop {
-            let c: BinderDriverReturnProtocol = d.read_u32::<LittleEndian>()
-                .chain_err(|| "Invalid read reply")?.into();
+            let (c, d) = self.read_command()?;
+            let mut d = &d[..];
             info!("BinderDriverReturnProtocol is {:?}", c);
 
             match c {
-                BinderDriverReturnProtocol::BR_NOOP => (),
-                BinderDriverReturnProtocol::BR_ERROR => return Err("Binder error".into()),
-                BinderDriverReturnProtocol::BR_TRANSACTION_COMPLETE => (),
-                BinderDriverReturnProtocol::BR_SPAWN_LOOPER => (),
-                BinderDriverReturnProtocol::BR_REPLY => {
-                   if d.len() < size_of::<BinderTransactionData>() {
-                       return Err(format!("Reply data to short: {} vs {}", d.len(), size_of::<BinderTransactionData>()).into());
-                   }
-
-                   let td: BinderTransactionData = unsafe { ::std::ptr::read(d.as_ptr() as *const _) };
-                   // d = &d[size_of::<binder_transaction_data>()..];
-
-                   debug!("Target: {:?} Cookie: {:?} Code: {}", td.target, td.cookie, td.code);
-                   debug!("Flags: {:x}", td.flags);
-                   debug!("Sender pid: {} euid: {}", td.sender_pid, td.sender_euid);
-                   debug!("Data size: {} Offsets size: {}", td.data_size, td.offsets_size);
-
-                   if (td.flags & TransactionFlags::STATUS_CODE as u32) != 0 {
-                       let code = unsafe { *(td.data as *const u32) };
-                       debug!("Status code: {:x}", code);
-                       return Ok(CallResult::Reply(Reply::StatusCode(code)));
-                   }
-
-                   let r = if td.data_size > 0 {
-                       unsafe {
-                           let p = td.data as *const u8;
-                           from_raw_parts(p, td.data_size as usize).to_vec()
-                       }
-                   } else {
-                       vec![]
-                   };
-                   if !r.is_empty() {
-                       debug!("Data:");
-                       hex!(&r);
-                   }
-                   return Ok(CallResult::Reply(Reply::Data(r)));
-                },
+                BinderDriverReturnProtocol::BR_TRANSACTION_COMPLETE => {
+                    if !reply {
+                        return Ok(CallResult::Noop);
+                    }
+                }
+                BinderDriverReturnProtocol::BR_REPLY => return self.read_reply(&mut d),
+                BinderDriverReturnProtocol::BR_DEAD_REPLY => return Err(ErrorKind::DeadReply.into()),
                 BinderDriverReturnProtocol::BR_FAILED_REPLY => return Err("Transaction failed".into()),
-                _ => unimplemented!(),
-
+                _ => self.execute(c, &mut d)?,
             }
+        }
+    }
 
-            if d.is_empty() {
-                return Ok(CallResult::Noop);
+    /// The next command read by this thread. Queued commands are written
+    /// and the thread blocks in the driver if all read commands are processed.
+    fn read_command(&self) -> Result<(BinderDriverReturnProtocol, Vec<u8>)> {
+        loop {
+            if let Some(command) = ThreadState::with(self.id, |t| t.next_command(self.protocol))? {
+                return Ok(command);
             }
+            self.talk(true)?;
         }
     }
 
-    pub fn serve(&self) -> Result<()> {
-        info!("Entering looper");
-        let mut data = vec![];
-        data.write_u32::<LittleEndian>(BinderDriverCommandProtocol::BC_ENTER_LOOPER as u32)
-            .unwrap();
-        let mut d = BinderWriteRead {
-            write_size: data.len() as BinderSize,
-            write_buffer: (&mut data).as_mut_ptr() as BinderPtr,
-            ..Default::default()
-        };
-        unsafe {
-            binder_write_read(self.fd, &mut d).chain_err(
-                || "Failed to enter looper",
+    fn talk(&self, read: bool) -> Result<()> {
+        ThreadState::talk(self.id, &*self.transport, read)
+    }
+
+    /// Queue `command` with `data` to be written with the next read of this thread.
+    fn queue_command(&self, command: BinderDriverCommandProtocol, data: &[u8]) {
+        ThreadState::with(self.id, |t| {
+            t.output.write_u32::<LittleEndian>(command.code(self.protocol)).unwrap();
+            t.output.extend_from_slice(data);
+        });
+    }
+
+    /// Write the queued commands of this thread.
+    fn flush(&self) -> Result<()> {
+        self.talk(false)
+    }
+
+    fn read_reply(&self, d: &mut &[u8]) -> Result<CallResult> {
+        let td = self.read_transaction_data(d)?;
+        let buffer = Buffer::new(self.id, self.transport.clone(), self.protocol, &td);
+
+        if (td.flags & TransactionFlags::STATUS_CODE as u32) != 0 {
+            let code = buffer.data().read_u32::<LittleEndian>().chain_err(
+                || "Invalid status code reply",
             )?;
-        };
+            debug!("Status code: {:x}", code);
+            return Ok(CallResult::Reply(Reply::StatusCode(code)));
+        }
 
-        loop {
-            let mut read_buffer: [u8; READ_SIZE] = [0; READ_SIZE];
-            let bwr = BinderWriteRead {
-                write_buffer: 0,
-                read_size: size_of::<[u8; READ_SIZE]>() as BinderSize,
-                read_buffer: (&mut read_buffer as *mut [u8; READ_SIZE]) as BinderPtr,
-                ..Default::default()
-            };
-            unsafe {
-                binder_write_read(self.fd, &mut d).chain_err(
-                    || "Failed to enter looper",
+        let r = Parcel::from_buffer(buffer);
+        if !r.is_empty() {
+            debug!("Data:");
+            hex!(&r);
+        }
+        Ok(CallResult::Reply(Reply::Data(r)))
+    }
+
+    /// Process a return command that is not a response to an own transaction.
+    fn execute(&self, c: BinderDriverReturnProtocol, d: &mut &[u8]) -> Result<()> {
+        ThreadState::with(self.id, |t| t.executing += 1);
+        let result = self.execute_command(c, d);
+        ThreadState::with(self.id, |t| t.executing -= 1);
+        result
+    }
+
+    fn execute_command(&self, c: BinderDriverReturnProtocol, d: &mut &[u8]) -> Result<()> {
+        match c {
+            BinderDriverReturnProtocol::BR_NOOP |
+            BinderDriverReturnProtocol::BR_OK |
+            BinderDriverReturnProtocol::BR_FINISHED => (),
+            BinderDriverReturnProtocol::BR_ERROR => {
+                let e = d.read_i32::<LittleEndian>().chain_err(
+                    || "Invalid read reply",
                 )?;
-            };
+                return Err(format!("Binder error {}", e).into());
+            }
+            BinderDriverReturnProtocol::BR_SPAWN_LOOPER => self.spawn_looper(false)?,
+            BinderDriverReturnProtocol::BR_INCREFS |
+            BinderDriverReturnProtocol::BR_ACQUIRE |
+            BinderDriverReturnProtocol::BR_RELEASE |
+            BinderDriverReturnProtocol::BR_DECREFS => {
+                let ptr = self.read_word(d)?;
+                let cookie = self.read_word(d)?;
+                debug!("{:?} ptr: 0x{:x} cookie: 0x{:x}", c, ptr, cookie);
+                let mut done = vec![];
+                self.protocol.write_word(&mut done, ptr);
+                self.protocol.write_word(&mut done, cookie);
+                match c {
+                    BinderDriverReturnProtocol::BR_INCREFS => {
+                        self.nodes.inc_weak(cookie);
+                        self.queue_command(BinderDriverCommandProtocol::BC_INCREFS_DONE, &done);
+                    }
+                    BinderDriverReturnProtocol::BR_ACQUIRE => {
+                        self.nodes.inc_strong(cookie);
+                        self.queue_command(BinderDriverCommandProtocol::BC_ACQUIRE_DONE, &done);
+                    }
+                    BinderDriverReturnProtocol::BR_RELEASE => self.nodes.dec_strong(cookie),
+                    _ => self.nodes.dec_weak(cookie),
+                }
+            }
+            BinderDriverReturnProtocol::BR_TRANSACTION => {
+                let td = self.read_transaction_data(d)?;
+                self.transaction(&td)?;
+            }
+            BinderDriverReturnProtocol::BR_DEAD_BINDER => {
+                let cookie = self.read_word(d)?;
+                info!("Handle {} died", cookie);
+                let proxy = self.proxies.lock().unwrap().get(&(cookie as u32)).and_then(
+                    |p| p.upgrade(),
+                );
+                if let Some(proxy) = proxy {
+                    RemoteBinder::from_proxy(proxy).send_obituary();
+                }
+                let mut done = vec![];
+                self.protocol.write_word(&mut done, cookie);
+                self.queue_command(BinderDriverCommandProtocol::BC_DEAD_BINDER_DONE, &done);
+            }
+            BinderDriverReturnProtocol::BR_CLEAR_DEATH_NOTIFICATION_DONE => {
+                let cookie = self.read_word(d)?;
+                debug!("Cleared death notification of handle {}", cookie);
+            }
+            BinderDriverReturnProtocol::BR_REPLY |
+            BinderDriverReturnProtocol::BR_TRANSACTION_COMPLETE |
+            BinderDriverReturnProtocol::BR_DEAD_REPLY |
+            BinderDriverReturnProtocol::BR_FAILED_REPLY => {
+                return Err(format!("Unexpected {:?}", c).into());
+            }
+            BinderDriverReturnProtocol::BR_ACQUIRE_RESULT |
+            BinderDriverReturnProtocol::BR_ATTEMPT_ACQUIRE => {
+                // Never sent by the kernel, the payload is skipped with the command.
+                warn!("Ignoring unsupported {:?}", c);
+            }
+        }
+        Ok(())
+    }
 
-            let mut d = &read_buffer[..(bwr.read_consumed as usize)];
+    /// The pid of the process that sent the transaction handled by the
+    /// calling thread, or of this process outside of a transaction. The
+    /// driver reports 0 for one way transactions.
+    pub fn calling_pid(&self) -> BinderPid {
+        ThreadState::with(self.id, |t| t.calling)
+            .map(|c| c.0)
+            .unwrap_or_else(|| process::id() as BinderPid)
+    }
 
-            while ! d.is_empty() {
-                let c: BinderDriverReturnProtocol = d.read_u32::<LittleEndian>()
-                    .chain_err(|| "Invalid read reply")?.into();
-                info!("BinderDriverReturnProtocol is {:?}", c);
+    /// The effective uid of the process that sent the transaction handled
+    /// by the calling thread, or of this process outside of a transaction.
+    pub fn calling_uid(&self) -> BinderUid {
+        ThreadState::with(self.id, |t| t.calling)
+            .map(|c| c.1)
+            .unwrap_or_else(|| unsafe { geteuid() })
+    }
 
-                match c {
-                    BinderDriverReturnProtocol::BR_NOOP => (),
-                    BinderDriverReturnProtocol::BR_ERROR => return Err("Binder error".into()),
-                    BinderDriverReturnProtocol::BR_TRANSACTION_COMPLETE => (),
-                    BinderDriverReturnProtocol::BR_SPAWN_LOOPER => (),
-                    BinderDriverReturnProtocol::BR_REPLY => (),
-                    BinderDriverReturnProtocol::BR_FAILED_REPLY => return Err("Transaction failed".into()),
-                    _ => unimplemented!(),
+    /// The live proxies by handle.
+    pub(crate) fn proxies(&self) -> &Mutex<HashMap<u32, Weak<Proxy>>> {
+        &self.proxies
+    }
+
+    /// Take a weak and a strong reference to `handle`.
+    pub(crate) fn acquire(&self, handle: u32) -> Result<()> {
+        debug!("Acquiring handle {}", handle);
+        self.queue_command(BinderDriverCommandProtocol::BC_INCREFS, any_as_u8_slice(&handle));
+        self.queue_command(BinderDriverCommandProtocol::BC_ACQUIRE, any_as_u8_slice(&handle));
+        self.flush()
+    }
+
+    /// Drop the references taken by `acquire`.
+    pub(crate) fn release(&self, handle: u32) -> Result<()> {
+        debug!("Releasing handle {}", handle);
+        self.queue_command(BinderDriverCommandProtocol::BC_RELEASE, any_as_u8_slice(&handle));
+        self.queue_command(BinderDriverCommandProtocol::BC_DECREFS, any_as_u8_slice(&handle));
+        self.flush()
+    }
+
+    /// Ask for `BR_DEAD_BINDER` when the object behind `handle` dies.
+    pub(crate) fn request_death_notification(&self, handle: u32) -> Result<()> {
+        self.death_notification(BinderDriverCommandProtocol::BC_REQUEST_DEATH_NOTIFICATION, handle)
+    }
+
+    pub(crate) fn clear_death_notification(&self, handle: u32) -> Result<()> {
+        self.death_notification(BinderDriverCommandProtocol::BC_CLEAR_DEATH_NOTIFICATION, handle)
+    }
 
+    /// Death notifications are requested with the handle as cookie.
+    fn death_notification(&self, command: BinderDriverCommandProtocol, handle: u32) -> Result<()> {
+        let mut data = vec![];
+        data.write_u32::<LittleEndian>(handle).unwrap();
+        self.protocol.write_word(&mut data, u64::from(handle));
+        self.queue_command(command, &data);
+        self.flush()
+    }
+
+    /// Dispatch an incoming transaction to its object and send the reply.
+    fn transaction(&self, td: &BinderTransactionData) -> Result<()> {
+        // The buffer is freed once the parcel is dropped after the reply.
+        let mut data = Parcel::from_buffer(Buffer::new(self.id, self.transport.clone(), self.protocol, td));
+        let mut reply = Parcel::default();
+        let calling = ThreadState::with(self.id, |t| {
+            t.calling.replace((td.sender_pid, td.sender_euid))
+        });
+
+        let result = if td.code == Transaction::Ping as u32 {
+            Ok(())
+        } else {
+            // The context manager node is known to the driver as 0.
+            let object = if td.cookie == 0 {
+                self.context_object.lock().unwrap().clone()
+            } else {
+                self.nodes.get(td.cookie)
+            };
+            match object {
+                Some(object) => object.on_transact(td.code, &mut data, &mut reply, td.flags),
+                None => {
+                    warn!("Transaction for unknown object 0x{:x}", td.cookie);
+                    Err(StatusCode::DeadObject.into())
                 }
             }
+        };
+        ThreadState::with(self.id, |t| t.calling = calling);
+
+        if (td.flags & TransactionFlags::ONE_WAY as u32) != 0 {
+            if let Err(e) = result {
+                warn!("One way transaction {} failed: {}", td.code, e);
+            }
+            return Ok(());
+        }
+
+        match result {
+            Ok(()) => self.reply(&reply, 0),
+            Err(e) => {
+                warn!("Transaction {} failed: {}", td.code, e);
+                let mut status = Parcel::default();
+                status.put_i32(e.status())?;
+                self.reply(&status, TransactionFlags::STATUS_CODE as u32)
+            }
         }
     }
 
+    fn reply(&self, parcel: &Parcel, flags: u32) -> Result<()> {
+        debug!("Reply transaction data:");
+        hex!(parcel);
+
+        self.send(BinderDriverCommandProtocol::BC_REPLY, parcel, 0, 0, flags, false)
+            .map(|_| ())
+    }
+
+    /// Write `parcel` with `command` and wait for the response. The local
+    /// objects referenced by the parcel are registered.
+    fn send(
+        &self,
+        command: BinderDriverCommandProtocol,
+        parcel: &Parcel,
+        target: u32,
+        code: u32,
+        flags: u32,
+        reply: bool,
+    ) -> Result<CallResult> {
+        let converted;
+        let parcel = if parcel.protocol() == self.protocol {
+            parcel
+        } else {
+            converted = parcel.to_protocol(self.protocol)?;
+            &converted
+        };
+
+        for object in parcel.objects() {
+            self.register_object(object.clone());
+        }
+
+        // Both buffers are read by the driver while waiting for the response.
+        let mut offsets = vec![];
+        for offset in parcel.offsets() {
+            self.protocol.write_word(&mut offsets, *offset);
+        }
+        let td = BinderTransactionData {
+            target,
+            cookie: 0,
+            code,
+            flags,
+            sender_pid: 0,
+            sender_euid: 0,
+            data_size: parcel.len() as BinderSize,
+            offsets_size: offsets.len() as BinderSize,
+            data: parcel.as_ptr() as BinderPtr,
+            offsets: offsets.as_ptr() as BinderPtr,
+        };
+
+        let mut out = vec![];
+        out.write_u32::<LittleEndian>(command.code(self.protocol)).unwrap();
+        td.encode(self.protocol, &mut out);
+        let result = self.wait_for_response(&out, reply);
+        if result.is_err() {
+            // The driver doesn't reference objects of undelivered transactions
+            for object in parcel.objects() {
+                self.nodes.forget(object.cookie());
+            }
+        }
+        result
+    }
+
+    fn read_transaction_data(&self, d: &mut &[u8]) -> Result<BinderTransactionData> {
+        let td = BinderTransactionData::decode(self.protocol, d)?;
+
+        debug!("Target: {:?} Cookie: {:?} Code: {}", td.target, td.cookie, td.code);
+        debug!("Flags: {:x}", td.flags);
+        debug!("Sender pid: {} euid: {}", td.sender_pid, td.sender_euid);
+        debug!("Data size: {} Offsets size: {}", td.data_size, td.offsets_size);
+        Ok(td)
+    }
+
+    /// Read a `binder_uintptr_t` or `binder_size_t`.
+    fn read_word(&self, d: &mut &[u8]) -> Result<u64> {
+        self.protocol.read_word(d).chain_err(|| "Invalid read reply")
+    }
 }
 
 impl Drop for Binder {
     fn drop(&mut self) {
-        unsafe {
-            binder_thread_exit(self.fd, 0).expect("Failed to exit binder");
-        }
-        close(self.fd).unwrap_or_else(|_| {
-            error!("Failed to close");
+        self.transport.thread_exit().unwrap_or_else(|_| {
+            error!("Failed to exit binder thread");
         });
-        info!("Dropped binder with fd {}", self.fd);
+        ThreadState::remove(self.id);
+        info!("Dropped binder");
     }
 }
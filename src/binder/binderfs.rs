@@ -0,0 +1,224 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use errors::*;
+use nix::fcntl::*;
+use nix::fcntl::OFlag;
+use nix::mount::{mount, umount, MsFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::close;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use super::BinderBuilder;
+
+/// Maximum length of a device name (`BINDERFS_MAX_NAME`).
+const MAX_NAME: usize = 255;
+const CONTROL: &str = "binder-control";
+const FEATURES: &str = "features";
+
+/// `struct binderfs_device` passed to `BINDER_CTL_ADD`.
+#[repr(C)]
+struct CtlDevice {
+    name: [u8; MAX_NAME + 1],
+    major: u32,
+    minor: u32,
+}
+
+impl CtlDevice {
+    /// The request to add the device `name`.
+    fn new(name: &str) -> Result<CtlDevice> {
+        if name.is_empty() || name.len() > MAX_NAME || name.contains('/') {
+            return Err(format!("Invalid binder device name {:?}", name).into());
+        }
+        let mut device = CtlDevice {
+            name: [0; MAX_NAME + 1],
+            major: 0,
+            minor: 0,
+        };
+        device.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(device)
+    }
+}
+
+ioctl!(readwrite binder_ctl_add with b'b', 1; CtlDevice);
+
+/// A binder device allocated in a binderfs instance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinderFsDevice {
+    pub name: String,
+    pub path: PathBuf,
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl BinderFsDevice {
+    /// A builder opening this device.
+    pub fn builder(&self) -> BinderBuilder {
+        BinderBuilder::new().device(&self.path)
+    }
+}
+
+/// A binderfs instance, i.e a set of binder devices independent of the
+/// ones of other instances.
+///
+/// Devices are allocated with `BINDER_CTL_ADD` on the `binder-control`
+/// node of the instance. This requires a kernel built with
+/// `CONFIG_ANDROID_BINDERFS` and usually `CAP_SYS_ADMIN`.
+#[derive(Debug)]
+pub struct BinderFs {
+    root: PathBuf,
+    /// Whether the instance was mounted by us and is unmounted on drop.
+    mounted: bool,
+}
+
+impl BinderFs {
+    /// Mount a new binderfs instance at the existing directory `root`. The
+    /// instance is unmounted when dropped.
+    pub fn mount<P: AsRef<Path>>(root: P) -> Result<BinderFs> {
+        let root = root.as_ref();
+        mount(
+            Some("binder"),
+            root,
+            Some("binder"),
+            MsFlags::empty(),
+            None::<&str>,
+        ).chain_err(|| format!("Failed to mount binderfs at {}", root.display()))?;
+        info!("Mounted binderfs at {}", root.display());
+        Ok(BinderFs {
+            root: root.to_path_buf(),
+            mounted: true,
+        })
+    }
+
+    /// Use the binderfs instance mounted at `root`, e.g `/dev/binderfs`.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<BinderFs> {
+        let root = root.as_ref();
+        if !root.join(CONTROL).exists() {
+            return Err(format!("No binderfs mounted at {}", root.display()).into());
+        }
+        Ok(BinderFs {
+            root: root.to_path_buf(),
+            mounted: false,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Allocate the device `name`.
+    pub fn add_device(&self, name: &str) -> Result<BinderFsDevice> {
+        let mut device = CtlDevice::new(name)?;
+        let control = self.root.join(CONTROL);
+        let mut flags = OFlag::empty();
+        flags.set(O_RDWR, true);
+        flags.set(O_CLOEXEC, true);
+        let fd = open(&control, flags, Mode::empty()).chain_err(|| {
+            format!("Failed to open {}", control.display())
+        })?;
+        let result = unsafe { binder_ctl_add(fd, &mut device) };
+        close(fd).ok();
+        result.chain_err(|| format!("Failed to add binder device {}", name))?;
+
+        let device = BinderFsDevice {
+            name: name.to_owned(),
+            path: self.root.join(name),
+            major: device.major,
+            minor: device.minor,
+        };
+        info!("Added binder device {} ({}:{})", device.path.display(), device.major, device.minor);
+        Ok(device)
+    }
+
+    /// Remove the device `name`. Processes that opened it keep using it.
+    pub fn remove_device(&self, name: &str) -> Result<()> {
+        fs::remove_file(self.root.join(name)).chain_err(|| {
+            format!("Failed to remove binder device {}", name)
+        })
+    }
+
+    /// The capabilities announced in `features/`, e.g `oneway_spam_detection`.
+    /// Kernels without the directory announce none.
+    pub fn features(&self) -> Result<HashMap<String, bool>> {
+        let mut features = HashMap::new();
+        let dir = self.root.join(FEATURES);
+        if !dir.exists() {
+            return Ok(features);
+        }
+        for entry in fs::read_dir(&dir).chain_err(|| "Failed to list binderfs features")? {
+            let entry = entry.chain_err(|| "Failed to list binderfs features")?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let enabled = read_feature(&entry.path())?;
+            features.insert(name, enabled);
+        }
+        Ok(features)
+    }
+
+    /// Whether the feature `name` is announced and enabled.
+    pub fn has_feature(&self, name: &str) -> bool {
+        read_feature(&self.root.join(FEATURES).join(name)).unwrap_or(false)
+    }
+}
+
+fn read_feature(path: &Path) -> Result<bool> {
+    let value = fs::read_to_string(path).chain_err(|| {
+        format!("Failed to read {}", path.display())
+    })?;
+    Ok(value.trim() == "1")
+}
+
+impl Drop for BinderFs {
+    fn drop(&mut self) {
+        if self.mounted {
+            umount(&self.root).unwrap_or_else(|e| {
+                error!("Failed to unmount {}: {}", self.root.display(), e);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use super::{CtlDevice, MAX_NAME};
+
+    #[test]
+    fn device_names_are_validated() {
+        assert!(CtlDevice::new("").is_err());
+        assert!(CtlDevice::new("dev/binder").is_err());
+        assert!(CtlDevice::new(&"b".repeat(MAX_NAME + 1)).is_err());
+
+        let name = "b".repeat(MAX_NAME);
+        let device = CtlDevice::new(&name).unwrap();
+        assert_eq!(&device.name[..MAX_NAME], name.as_bytes());
+        assert_eq!(device.name[MAX_NAME], 0);
+    }
+
+    #[test]
+    fn ctl_add_request() {
+        let device = CtlDevice::new("binder").unwrap();
+        assert_eq!(&device.name[..7], b"binder\0");
+        assert_eq!((device.major, device.minor), (0, 0));
+
+        // struct binderfs_device { char name[256]; __u32 major; __u32 minor; }
+        assert_eq!(mem::size_of::<CtlDevice>(), 264);
+        let base = &device as *const CtlDevice as usize;
+        assert_eq!(&device.major as *const u32 as usize - base, 256);
+        assert_eq!(&device.minor as *const u32 as usize - base, 260);
+        // _IOWR('b', 1, struct binderfs_device)
+        assert_eq!(iorw!(b'b', 1, mem::size_of::<CtlDevice>()), 0xc108_6201);
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::fmt;
+use std::slice::from_raw_parts;
+use std::sync::Arc;
+use super::thread_state::ThreadState;
+use super::transport::Transport;
+use types::*;
+
+/// A transaction or reply the driver placed in the mapped region of this
+/// process.
+///
+/// The data is read in place. The buffer is handed back to the driver with
+/// `BC_FREE_BUFFER` when dropped. The command is written right away unless
+/// the dropping thread talks to the driver anyway, e.g a looper.
+pub struct Buffer {
+    id: usize,
+    /// Keeps the mapping alive.
+    transport: Arc<dyn Transport>,
+    protocol: Protocol,
+    data: BinderPtr,
+    data_size: usize,
+    offsets: BinderPtr,
+    offsets_size: usize,
+}
+
+// The mapping is read only and released with the transport.
+unsafe impl Send for Buffer {}
+unsafe impl Sync for Buffer {}
+
+impl Buffer {
+    /// Take ownership of the buffer described by `td` read from binder `id`.
+    pub(crate) fn new(id: usize, transport: Arc<dyn Transport>, protocol: Protocol, td: &BinderTransactionData) -> Buffer {
+        Buffer {
+            id,
+            transport,
+            protocol,
+            data: td.data,
+            data_size: td.data_size as usize,
+            offsets: td.offsets,
+            offsets_size: td.offsets_size as usize,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        if self.data_size == 0 {
+            return &[];
+        }
+        unsafe { from_raw_parts(self.data as *const u8, self.data_size) }
+    }
+
+    /// Positions of the flat binder objects in `data`.
+    pub fn offsets(&self) -> Vec<BinderSize> {
+        let n = self.offsets_size / self.protocol.word_size();
+        if n == 0 {
+            return vec![];
+        }
+        let mut d = unsafe { from_raw_parts(self.offsets as *const u8, n * self.protocol.word_size()) };
+        (0..n).filter_map(|_| self.protocol.read_word(&mut d).ok()).collect()
+    }
+
+    /// The protocol version the flat binder objects in `data` are encoded in.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+}
+
+impl fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Buffer {{ data: 0x{:x}, size: {} }}", self.data, self.data_size)
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        debug!("Freeing buffer 0x{:x}", self.data);
+        ThreadState::with(self.id, |t| {
+            let command = BinderDriverCommandProtocol::BC_FREE_BUFFER;
+            t.output
+                .write_u32::<LittleEndian>(command.code(self.protocol))
+                .unwrap();
+            self.protocol.write_word(&mut t.output, self.data);
+        });
+        if ThreadState::flush_needed(self.id) {
+            ThreadState::talk(self.id, &*self.transport, false).unwrap_or_else(|e| {
+                error!("Failed to free buffer 0x{:x}: {}", self.data, e);
+            });
+        }
+    }
+}
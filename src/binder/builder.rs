@@ -0,0 +1,74 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use errors::*;
+use std::path::{Path, PathBuf};
+use super::binder::{Binder, MAX_THREADS};
+use super::device::{Device, DEFAULT_MAP_SIZE};
+
+/// The device used by framework processes.
+pub const BINDER_DEVICE: &str = "/dev/binder";
+/// The device used by HALs.
+pub const HWBINDER_DEVICE: &str = "/dev/hwbinder";
+/// The device used by vendor processes.
+pub const VNDBINDER_DEVICE: &str = "/dev/vndbinder";
+
+/// Configures how a `Binder` opens its device.
+#[derive(Debug, Clone)]
+pub struct BinderBuilder {
+    device: PathBuf,
+    map_size: usize,
+    max_threads: u32,
+}
+
+impl Default for BinderBuilder {
+    fn default() -> BinderBuilder {
+        BinderBuilder {
+            device: PathBuf::from(BINDER_DEVICE),
+            map_size: DEFAULT_MAP_SIZE,
+            max_threads: MAX_THREADS,
+        }
+    }
+}
+
+impl BinderBuilder {
+    pub fn new() -> BinderBuilder {
+        BinderBuilder::default()
+    }
+
+    /// Path of the device to open, e.g `/dev/hwbinder` or a device in a
+    /// binderfs mount. Defaults to `/dev/binder`.
+    pub fn device<P: AsRef<Path>>(mut self, device: P) -> BinderBuilder {
+        self.device = device.as_ref().to_path_buf();
+        self
+    }
+
+    /// Size of the mapping the driver places received transactions in.
+    pub fn map_size(mut self, map_size: usize) -> BinderBuilder {
+        self.map_size = map_size;
+        self
+    }
+
+    /// Number of looper threads the driver may ask to spawn.
+    pub fn max_threads(mut self, max_threads: u32) -> BinderBuilder {
+        self.max_threads = max_threads;
+        self
+    }
+
+    pub fn build(self) -> Result<Binder> {
+        let device = Device::open(&self.device, self.map_size)?;
+        Binder::from_transport(Box::new(device), self.max_threads)
+    }
+}
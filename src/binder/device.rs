@@ -0,0 +1,166 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use errors::*;
+use nix::fcntl::*;
+use nix::fcntl::OFlag;
+use nix::libc::c_void;
+use nix::sys::mman::*;
+use nix::sys::stat::Mode;
+use nix::unistd::close;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::ptr::null_mut;
+use super::transport::Transport;
+use types::*;
+
+const BINDER_IOC_MAGIC: u8 = b'b';
+/// The mapping size used by libbinder.
+pub const DEFAULT_MAP_SIZE: usize = 1024 * 1024 - 4096 * 2;
+
+ioctl!(readwrite binder_write_read with BINDER_IOC_MAGIC, 1; BinderWriteRead);
+ioctl!(readwrite binder_write_read_32 with BINDER_IOC_MAGIC, 1; BinderWriteRead32);
+ioctl!(write_ptr _binder_set_idle_timeout with BINDER_IOC_MAGIC, 3; i64);
+ioctl!(write_ptr binder_set_max_threads with BINDER_IOC_MAGIC, 5; u32);
+ioctl!(write_ptr _binder_set_idle_priotity with BINDER_IOC_MAGIC, 6; i32);
+ioctl!(write_int binder_set_context_mgr with BINDER_IOC_MAGIC, 7);
+ioctl!(write_int binder_thread_exit with BINDER_IOC_MAGIC, 8);
+ioctl!(readwrite binder_version with BINDER_IOC_MAGIC, 9; BinderVersion);
+
+/// A binder device node opened from the file system, e.g `/dev/binder`.
+///
+/// The device owns the read only mapping the kernel places transaction
+/// buffers in. Both are released when the device is dropped.
+pub struct Device {
+    fd: RawFd,
+    mapped: *mut c_void,
+    map_size: usize,
+    /// Selects the layout of `BINDER_WRITE_READ`.
+    protocol: Protocol,
+}
+
+// The mapping is written by the kernel only and the fd is used via ioctls.
+unsafe impl Send for Device {}
+unsafe impl Sync for Device {}
+
+impl Device {
+    /// Open the device at `path` and map `map_size` bytes for transaction buffers.
+    pub fn open(path: &Path, map_size: usize) -> Result<Device> {
+        let mut flags = OFlag::empty();
+        flags.set(O_RDWR, true);
+        flags.set(O_CLOEXEC, true);
+
+        let fd = open(path, flags, Mode::empty()).chain_err(|| {
+            format!("Failed to open {}", path.display())
+        })?;
+
+        let mut prot_flags = ProtFlags::empty();
+        prot_flags.set(PROT_READ, true);
+        let mut flags = MapFlags::empty();
+        flags.set(MAP_PRIVATE, true);
+        flags.set(MAP_NORESERVE, true);
+        let mapped = unsafe {
+            mmap(null_mut(), map_size, prot_flags, flags, fd, 0)
+                .chain_err(|| "Failed to mmap")
+                .inspect_err(|_| {
+                    close(fd).ok();
+                })?
+        };
+        info!("Mapped {} bytes of {}", map_size, path.display());
+
+        let mut device = Device {
+            fd,
+            mapped,
+            map_size,
+            protocol: Protocol::default(),
+        };
+        device.protocol = Protocol::from_version(device.version()?)?;
+        info!("{} speaks protocol version {}", path.display(), device.protocol.version());
+        Ok(device)
+    }
+}
+
+impl Transport for Device {
+    fn version(&self) -> Result<i32> {
+        let mut version = BinderVersion::default();
+        unsafe {
+            binder_version(self.fd, &mut version).chain_err(
+                || "Failed to get version",
+            )?;
+        }
+        Ok(version.protocol_version)
+    }
+
+    fn write_read(&self, bwr: &mut BinderWriteRead) -> Result<()> {
+        match self.protocol {
+            Protocol::V7 => {
+                let mut bwr32 = BinderWriteRead32::from(&*bwr);
+                unsafe {
+                    binder_write_read_32(self.fd, &mut bwr32).chain_err(
+                        || "Failed to write/read",
+                    )?;
+                }
+                bwr.write_consumed = BinderSize::from(bwr32.write_consumed);
+                bwr.read_consumed = BinderSize::from(bwr32.read_consumed);
+            }
+            Protocol::V8 => unsafe {
+                binder_write_read(self.fd, bwr).chain_err(
+                    || "Failed to write/read",
+                )?;
+            },
+        }
+        Ok(())
+    }
+
+    fn set_max_threads(&self, threads: u32) -> Result<()> {
+        unsafe {
+            binder_set_max_threads(self.fd, &threads).chain_err(
+                || "Failed to set max threads",
+            )?;
+        }
+        Ok(())
+    }
+
+    fn set_context_mgr(&self) -> Result<()> {
+        unsafe {
+            binder_set_context_mgr(self.fd, 0).chain_err(
+                || "Failed to become context manager",
+            )?;
+        }
+        Ok(())
+    }
+
+    fn thread_exit(&self) -> Result<()> {
+        unsafe {
+            binder_thread_exit(self.fd, 0).chain_err(
+                || "Failed to exit binder thread",
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.mapped, self.map_size).unwrap_or_else(|_| {
+                error!("Failed to unmap");
+            });
+        }
+        close(self.fd).unwrap_or_else(|_| {
+            error!("Failed to close");
+        });
+        info!("Closed binder device with fd {}", self.fd);
+    }
+}
@@ -0,0 +1,1052 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An in process binder driver.
+//!
+//! `MockDriver` plays the role of the kernel: every `MockProcess` opened from
+//! it is a simulated process with its own handle table, todo list and
+//! transaction buffers. Transactions are routed between the processes with
+//! the same rules the kernel applies, so the complete stack from `Binder` up
+//! to `ServiceManager` can be exercised without a binder device.
+//!
+//! The mock speaks protocol version 8. Buffers are handed out as plain
+//! pointers into the heap of the calling process.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use errors::*;
+use std::collections::{HashMap, VecDeque};
+use std::mem::{size_of, size_of_val};
+use std::ptr;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+use super::transport::Transport;
+use types::*;
+use utils::read_struct;
+
+const PROTOCOL: Protocol = Protocol::V8;
+
+type NodeId = usize;
+type ThreadRef = (BinderPid, ThreadId);
+
+struct Node {
+    owner: BinderPid,
+    ptr: BinderPtr,
+    cookie: BinderPtr,
+    /// Number of references holding a strong count on the node.
+    internal_strong: usize,
+    /// Number of references to the node.
+    refs: usize,
+    /// Counts held by transaction buffers and unacknowledged commands.
+    local_strong: usize,
+    local_weak: usize,
+    /// Whether the owner was asked to keep a strong or weak reference.
+    has_strong: bool,
+    has_weak: bool,
+    /// Whether node work is queued for the owner.
+    queued: bool,
+}
+
+/// A reference to a node held by a process.
+struct Ref {
+    node: NodeId,
+    strong: usize,
+    weak: usize,
+    /// Cookie of a requested death notification.
+    death: Option<BinderPtr>,
+}
+
+/// A count taken by a transaction buffer that is released when the buffer is freed.
+enum BufferRef {
+    Local(NodeId, bool),
+    Handle(u32, bool),
+}
+
+struct Buffer {
+    /// Backing memory the process reads the transaction from.
+    _data: Vec<u64>,
+    refs: Vec<BufferRef>,
+}
+
+struct Transaction {
+    code: u32,
+    flags: u32,
+    sender_pid: BinderPid,
+    sender_euid: BinderUid,
+    data: BinderPtr,
+    data_size: BinderSize,
+    offsets: BinderPtr,
+    offsets_size: BinderSize,
+}
+
+enum Work {
+    TransactionComplete,
+    Transaction {
+        ptr: BinderPtr,
+        cookie: BinderPtr,
+        transaction: Transaction,
+        from: Option<ThreadRef>,
+    },
+    Reply(Transaction),
+    /// Reference count changes of a node to report to its owner.
+    Node(NodeId),
+    DeadBinder(BinderPtr),
+    ClearDeathNotificationDone(BinderPtr),
+    DeadReply,
+    FailedReply,
+}
+
+enum Frame {
+    /// A synchronous transaction sent by this thread waiting for its reply.
+    Outgoing,
+    /// A synchronous transaction received by this thread that must be replied to.
+    Incoming(ThreadRef),
+}
+
+#[derive(Default)]
+struct Thread {
+    todo: VecDeque<Work>,
+    stack: Vec<Frame>,
+    looper: bool,
+}
+
+struct Process {
+    euid: BinderUid,
+    max_threads: u32,
+    /// Looper threads requested with `BR_SPAWN_LOOPER` and not yet registered.
+    requested_threads: u32,
+    /// Looper threads registered on request.
+    started_threads: u32,
+    /// Looper threads blocked waiting for process work.
+    waiting_threads: usize,
+    /// Nodes owned by the process by their pointer.
+    nodes: HashMap<BinderPtr, NodeId>,
+    /// References to nodes by handle and handles by node.
+    refs: HashMap<u32, Ref>,
+    handles: HashMap<NodeId, u32>,
+    next_handle: u32,
+    todo: VecDeque<Work>,
+    threads: HashMap<ThreadId, Thread>,
+    buffers: HashMap<BinderPtr, Buffer>,
+}
+
+#[derive(Default)]
+struct State {
+    processes: HashMap<BinderPid, Process>,
+    nodes: HashMap<NodeId, Node>,
+    context_mgr: Option<NodeId>,
+    next_pid: BinderPid,
+    next_node: NodeId,
+}
+
+struct Inner {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// In process replacement of the binder kernel driver.
+#[derive(Clone)]
+pub struct MockDriver {
+    inner: Arc<Inner>,
+}
+
+/// A simulated process attached to a `MockDriver`.
+///
+/// Use it as the `Transport` of a `Binder`. Dropping the process releases
+/// all its nodes and fails transactions waiting for it with `BR_DEAD_REPLY`.
+pub struct MockProcess {
+    driver: MockDriver,
+    pid: BinderPid,
+}
+
+impl Default for MockDriver {
+    fn default() -> Self {
+        MockDriver::new()
+    }
+}
+
+impl MockDriver {
+    pub fn new() -> MockDriver {
+        MockDriver {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State::default()),
+                cond: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Attach a new process running as uid 0.
+    pub fn open(&self) -> MockProcess {
+        self.open_as(0)
+    }
+
+    /// Attach a new process running as `euid`.
+    pub fn open_as(&self, euid: BinderUid) -> MockProcess {
+        let mut state = self.lock();
+        state.next_pid += 1;
+        let pid = state.next_pid;
+        state.processes.insert(
+            pid,
+            Process {
+                euid,
+                max_threads: 0,
+                requested_threads: 0,
+                started_threads: 0,
+                waiting_threads: 0,
+                nodes: HashMap::new(),
+                refs: HashMap::new(),
+                handles: HashMap::new(),
+                next_handle: 1,
+                todo: VecDeque::new(),
+                threads: HashMap::new(),
+                buffers: HashMap::new(),
+            },
+        );
+        debug!("Mock process {} opened", pid);
+        MockProcess {
+            driver: self.clone(),
+            pid,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MockProcess {
+    pub fn pid(&self) -> BinderPid {
+        self.pid
+    }
+
+    /// Number of transaction buffers delivered to the process and not freed yet.
+    pub fn allocated_buffers(&self) -> usize {
+        self.driver.lock().process(self.pid).buffers.len()
+    }
+
+    fn read<'a>(&'a self, mut state: MutexGuard<'a, State>, tid: ThreadId, buffer: &mut [u8]) -> Result<usize> {
+        let mut out = vec![];
+        out.write_u32::<LittleEndian>(BinderDriverReturnProtocol::BR_NOOP.code(PROTOCOL))
+            .unwrap();
+        let min_size = ::std::cmp::max(
+            size_of::<u32>() + BinderTransactionData::size(PROTOCOL),
+            4 * (size_of::<u32>() + 2 * PROTOCOL.word_size()),
+        );
+
+        while buffer.len() - out.len() >= min_size {
+            let work = match state.next_work(self.pid, tid) {
+                Some(work) => work,
+                None if out.len() == size_of::<u32>() => {
+                    let idle = state.idle_looper(self.pid, tid);
+                    if idle {
+                        state.process(self.pid).waiting_threads += 1;
+                    }
+                    state = self.driver.inner.cond.wait(state).unwrap_or_else(|e| e.into_inner());
+                    if idle {
+                        state.process(self.pid).waiting_threads -= 1;
+                    }
+                    continue;
+                }
+                None => break,
+            };
+
+            match work {
+                Work::TransactionComplete => {
+                    out.write_u32::<LittleEndian>(BinderDriverReturnProtocol::BR_TRANSACTION_COMPLETE.code(PROTOCOL))
+                        .unwrap();
+                }
+                Work::DeadReply => {
+                    out.write_u32::<LittleEndian>(BinderDriverReturnProtocol::BR_DEAD_REPLY.code(PROTOCOL))
+                        .unwrap();
+                }
+                Work::FailedReply => {
+                    out.write_u32::<LittleEndian>(BinderDriverReturnProtocol::BR_FAILED_REPLY.code(PROTOCOL))
+                        .unwrap();
+                }
+                Work::Transaction { ptr, cookie, transaction, from } => {
+                    out.write_u32::<LittleEndian>(BinderDriverReturnProtocol::BR_TRANSACTION.code(PROTOCOL))
+                        .unwrap();
+                    transaction.to_data(ptr as u32, cookie).encode(PROTOCOL, &mut out);
+                    if let Some(from) = from {
+                        state.thread(self.pid, tid).stack.push(Frame::Incoming(from));
+                    }
+                    break;
+                }
+                Work::Reply(transaction) => {
+                    out.write_u32::<LittleEndian>(BinderDriverReturnProtocol::BR_REPLY.code(PROTOCOL))
+                        .unwrap();
+                    transaction.to_data(0, 0).encode(PROTOCOL, &mut out);
+                    break;
+                }
+                Work::Node(node) => state.node_work(node, &mut out),
+                Work::DeadBinder(cookie) => {
+                    out.write_u32::<LittleEndian>(BinderDriverReturnProtocol::BR_DEAD_BINDER.code(PROTOCOL))
+                        .unwrap();
+                    PROTOCOL.write_word(&mut out, cookie);
+                    // The receiver may issue transactions in response.
+                    break;
+                }
+                Work::ClearDeathNotificationDone(cookie) => {
+                    out.write_u32::<LittleEndian>(
+                        BinderDriverReturnProtocol::BR_CLEAR_DEATH_NOTIFICATION_DONE.code(PROTOCOL),
+                    ).unwrap();
+                    PROTOCOL.write_word(&mut out, cookie);
+                }
+            }
+        }
+
+        if state.spawn_looper(self.pid, tid) {
+            // Like the kernel replace the leading BR_NOOP.
+            (&mut out[..size_of::<u32>()])
+                .write_u32::<LittleEndian>(BinderDriverReturnProtocol::BR_SPAWN_LOOPER.code(PROTOCOL))
+                .unwrap();
+        }
+
+        buffer[..out.len()].copy_from_slice(&out);
+        Ok(out.len())
+    }
+}
+
+impl Transport for MockProcess {
+    fn version(&self) -> Result<i32> {
+        Ok(PROTOCOL.version())
+    }
+
+    fn write_read(&self, bwr: &mut BinderWriteRead) -> Result<()> {
+        let tid = thread::current().id();
+        let mut state = self.driver.lock();
+
+        if bwr.write_size > bwr.write_consumed {
+            let write = unsafe {
+                let p = (bwr.write_buffer + bwr.write_consumed) as *const u8;
+                from_raw_parts(p, (bwr.write_size - bwr.write_consumed) as usize)
+            };
+            let mut w = write;
+            while !w.is_empty() {
+                state.command(self.pid, tid, &mut w)?;
+                bwr.write_consumed = (write.len() - w.len()) as BinderSize;
+            }
+            self.driver.inner.cond.notify_all();
+        }
+
+        if bwr.read_size > bwr.read_consumed {
+            let read = unsafe {
+                let p = (bwr.read_buffer + bwr.read_consumed) as *mut u8;
+                from_raw_parts_mut(p, (bwr.read_size - bwr.read_consumed) as usize)
+            };
+            let n = self.read(state, tid, read)?;
+            bwr.read_consumed += n as BinderSize;
+        }
+
+        Ok(())
+    }
+
+    fn set_max_threads(&self, threads: u32) -> Result<()> {
+        let mut state = self.driver.lock();
+        state.process(self.pid).max_threads = threads;
+        Ok(())
+    }
+
+    fn set_context_mgr(&self) -> Result<()> {
+        let mut state = self.driver.lock();
+        if state.context_mgr.is_some() {
+            return Err("Context manager already set".into());
+        }
+        let node = state.new_node(self.pid, 0, 0);
+        {
+            // Like the kernel pin the node without asking the owner for references.
+            let n = state.nodes.get_mut(&node).unwrap();
+            n.local_strong += 1;
+            n.local_weak += 1;
+            n.has_strong = true;
+            n.has_weak = true;
+        }
+        state.process(self.pid).nodes.insert(0, node);
+        state.context_mgr = Some(node);
+        info!("Mock process {} is context manager", self.pid);
+        Ok(())
+    }
+
+    fn thread_exit(&self) -> Result<()> {
+        let tid = thread::current().id();
+        let mut state = self.driver.lock();
+        if let Some(thread) = state.process(self.pid).threads.remove(&tid) {
+            state.release_thread(thread);
+        }
+        self.driver.inner.cond.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for MockProcess {
+    fn drop(&mut self) {
+        let mut state = self.driver.lock();
+        state.release_process(self.pid);
+        self.driver.inner.cond.notify_all();
+        debug!("Mock process {} released", self.pid);
+    }
+}
+
+impl Transaction {
+    fn to_data(&self, target: u32, cookie: BinderPtr) -> BinderTransactionData {
+        BinderTransactionData {
+            target,
+            cookie,
+            code: self.code,
+            flags: self.flags,
+            sender_pid: self.sender_pid,
+            sender_euid: self.sender_euid,
+            data_size: self.data_size,
+            offsets_size: self.offsets_size,
+            data: self.data,
+            offsets: self.offsets,
+        }
+    }
+}
+
+impl State {
+    fn process(&mut self, pid: BinderPid) -> &mut Process {
+        self.processes.get_mut(&pid).expect("Invalid mock process")
+    }
+
+    fn thread(&mut self, pid: BinderPid, tid: ThreadId) -> &mut Thread {
+        self.process(pid).threads.entry(tid).or_default()
+    }
+
+    fn new_node(&mut self, owner: BinderPid, ptr: BinderPtr, cookie: BinderPtr) -> NodeId {
+        self.next_node += 1;
+        self.nodes.insert(
+            self.next_node,
+            Node {
+                owner,
+                ptr,
+                cookie,
+                internal_strong: 0,
+                refs: 0,
+                local_strong: 0,
+                local_weak: 0,
+                has_strong: false,
+                has_weak: false,
+                queued: false,
+            },
+        );
+        self.next_node
+    }
+
+    /// Queue node work for the owner if its reference state changed or
+    /// delete the node if it is no longer referenced. The work goes to
+    /// `thread` if given and to the owning process otherwise.
+    fn update_node(&mut self, id: NodeId, thread: Option<ThreadRef>) {
+        let context_mgr = self.context_mgr == Some(id);
+        let (owner, change, unused) = match self.nodes.get(&id) {
+            Some(n) => {
+                let strong = n.internal_strong > 0 || n.local_strong > 0;
+                let weak = strong || n.refs > 0 || n.local_weak > 0;
+                let change = strong != n.has_strong || weak != n.has_weak;
+                (n.owner, change && !n.queued, !weak && !n.has_weak && !n.queued && !context_mgr)
+            }
+            None => return,
+        };
+
+        if !self.processes.contains_key(&owner) {
+            // Nodes of dead processes live as long as references to them exist.
+            if self.nodes[&id].refs == 0 {
+                self.nodes.remove(&id);
+            }
+        } else if change {
+            self.nodes.get_mut(&id).unwrap().queued = true;
+            match thread {
+                Some((pid, tid)) if pid == owner => self.thread(pid, tid).todo.push_back(Work::Node(id)),
+                _ => self.process(owner).todo.push_back(Work::Node(id)),
+            }
+        } else if unused {
+            let ptr = self.nodes.remove(&id).unwrap().ptr;
+            self.process(owner).nodes.remove(&ptr);
+        }
+    }
+
+    /// Report the reference count changes of a node to its owner.
+    fn node_work(&mut self, id: NodeId, out: &mut Vec<u8>) {
+        if let Some(n) = self.nodes.get_mut(&id) {
+            n.queued = false;
+            let strong = n.internal_strong > 0 || n.local_strong > 0;
+            let weak = strong || n.refs > 0 || n.local_weak > 0;
+            let mut commands = vec![];
+            if weak && !n.has_weak {
+                n.has_weak = true;
+                n.local_weak += 1;
+                commands.push(BinderDriverReturnProtocol::BR_INCREFS);
+            }
+            if strong && !n.has_strong {
+                n.has_strong = true;
+                n.local_strong += 1;
+                commands.push(BinderDriverReturnProtocol::BR_ACQUIRE);
+            }
+            if !strong && n.has_strong {
+                n.has_strong = false;
+                commands.push(BinderDriverReturnProtocol::BR_RELEASE);
+            }
+            if !weak && n.has_weak {
+                n.has_weak = false;
+                commands.push(BinderDriverReturnProtocol::BR_DECREFS);
+            }
+            for c in commands {
+                out.write_u32::<LittleEndian>(c.code(PROTOCOL)).unwrap();
+                PROTOCOL.write_word(out, n.ptr);
+                PROTOCOL.write_word(out, n.cookie);
+            }
+        }
+        self.update_node(id, None);
+    }
+
+    /// The node referred to by `handle` in process `pid`.
+    fn lookup(&self, pid: BinderPid, handle: u32) -> Option<NodeId> {
+        match self.processes.get(&pid).and_then(|p| p.refs.get(&handle)) {
+            Some(r) => Some(r.node),
+            None if handle == 0 => self.context_mgr,
+            None => None,
+        }
+    }
+
+    /// Increment the reference of process `pid` to `node` and return its handle.
+    fn inc_ref(&mut self, pid: BinderPid, node: NodeId, strong: bool, thread: Option<ThreadRef>) -> u32 {
+        let handle = match self.process(pid).handles.get(&node).cloned() {
+            Some(handle) => handle,
+            None => {
+                let handle = if self.context_mgr == Some(node) {
+                    0
+                } else {
+                    let process = self.process(pid);
+                    process.next_handle += 1;
+                    process.next_handle - 1
+                };
+                let process = self.process(pid);
+                process.handles.insert(node, handle);
+                process.refs.insert(
+                    handle,
+                    Ref {
+                        node,
+                        strong: 0,
+                        weak: 0,
+                        death: None,
+                    },
+                );
+                self.nodes.get_mut(&node).unwrap().refs += 1;
+                handle
+            }
+        };
+
+        let first_strong = {
+            let r = self.process(pid).refs.get_mut(&handle).unwrap();
+            if strong {
+                r.strong += 1;
+                r.strong == 1
+            } else {
+                r.weak += 1;
+                false
+            }
+        };
+        if first_strong {
+            self.nodes.get_mut(&node).unwrap().internal_strong += 1;
+        }
+        self.update_node(node, thread);
+        handle
+    }
+
+    /// Decrement the reference `handle` of process `pid` and drop it if unused.
+    fn dec_ref(&mut self, pid: BinderPid, handle: u32, strong: bool) -> Result<()> {
+        let (node, last_strong, unused) = {
+            let r = self.process(pid).refs.get_mut(&handle).ok_or_else(|| {
+                Error::from(format!("Invalid handle {}", handle))
+            })?;
+            let count = if strong { &mut r.strong } else { &mut r.weak };
+            if *count == 0 {
+                return Err(format!("Reference count of handle {} underflow", handle).into());
+            }
+            *count -= 1;
+            (r.node, strong && r.strong == 0, r.strong == 0 && r.weak == 0)
+        };
+        if last_strong {
+            self.nodes.get_mut(&node).unwrap().internal_strong -= 1;
+        }
+        if unused {
+            let process = self.process(pid);
+            process.refs.remove(&handle);
+            process.handles.remove(&node);
+            self.nodes.get_mut(&node).unwrap().refs -= 1;
+        }
+        self.update_node(node, None);
+        Ok(())
+    }
+
+    /// Adjust the counts the owner of `ptr` holds via transaction buffers or pending acknowledges.
+    fn local_ref(&mut self, node: NodeId, strong: bool, inc: bool) {
+        if let Some(n) = self.nodes.get_mut(&node) {
+            let count = if strong { &mut n.local_strong } else { &mut n.local_weak };
+            if inc {
+                *count += 1;
+            } else if *count > 0 {
+                *count -= 1;
+            }
+        }
+        self.update_node(node, None);
+    }
+
+    fn free_buffer(&mut self, pid: BinderPid, buffer: Buffer) {
+        for r in buffer.refs {
+            match r {
+                BufferRef::Local(node, strong) => self.local_ref(node, strong, false),
+                BufferRef::Handle(handle, strong) => {
+                    self.dec_ref(pid, handle, strong).unwrap_or_else(|e| {
+                        warn!("Mock process {} failed to release buffer reference: {}", pid, e);
+                    })
+                }
+            }
+        }
+    }
+
+    /// Whether thread `tid` takes work queued to its process.
+    fn idle_looper(&mut self, pid: BinderPid, tid: ThreadId) -> bool {
+        let thread = self.thread(pid, tid);
+        thread.looper && thread.stack.is_empty()
+    }
+
+    /// Whether to ask the reading thread to spawn another looper because
+    /// no looper is waiting for work.
+    fn spawn_looper(&mut self, pid: BinderPid, tid: ThreadId) -> bool {
+        let looper = self.thread(pid, tid).looper;
+        let process = self.process(pid);
+        if looper && process.requested_threads == 0 && process.waiting_threads == 0 &&
+            process.started_threads < process.max_threads
+        {
+            process.requested_threads += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn next_work(&mut self, pid: BinderPid, tid: ThreadId) -> Option<Work> {
+        let process = self.process(pid);
+        let thread = process.threads.entry(tid).or_default();
+        if let Some(work) = thread.todo.pop_front() {
+            return Some(work);
+        }
+        if thread.looper && thread.stack.is_empty() {
+            process.todo.pop_front()
+        } else {
+            None
+        }
+    }
+
+    fn command(&mut self, pid: BinderPid, tid: ThreadId, w: &mut &[u8]) -> Result<()> {
+        let c = w.read_u32::<LittleEndian>().chain_err(
+            || "Invalid command",
+        )?;
+        let c = BinderDriverCommandProtocol::from_code(c, PROTOCOL).ok_or_else(|| {
+            Error::from(format!("Unknown command 0x{:x}", c))
+        })?;
+        debug!("Mock process {} command {:?}", pid, c);
+
+        match c {
+            BinderDriverCommandProtocol::BC_TRANSACTION |
+            BinderDriverCommandProtocol::BC_REPLY => {
+                let td = BinderTransactionData::decode(PROTOCOL, w)?;
+                let reply = c == BinderDriverCommandProtocol::BC_REPLY;
+                self.transaction(pid, tid, &td, reply);
+            }
+            BinderDriverCommandProtocol::BC_FREE_BUFFER => {
+                let buffer = read_word(w)?;
+                match self.process(pid).buffers.remove(&buffer) {
+                    Some(buffer) => self.free_buffer(pid, buffer),
+                    None => warn!("Mock process {} freed unknown buffer 0x{:x}", pid, buffer),
+                }
+            }
+            BinderDriverCommandProtocol::BC_INCREFS |
+            BinderDriverCommandProtocol::BC_ACQUIRE => {
+                let handle = read_struct::<u32>(w)?;
+                let strong = c == BinderDriverCommandProtocol::BC_ACQUIRE;
+                match self.lookup(pid, handle) {
+                    Some(node) => {
+                        self.inc_ref(pid, node, strong, None);
+                    }
+                    None => warn!("Mock process {} used invalid handle {}", pid, handle),
+                }
+            }
+            BinderDriverCommandProtocol::BC_RELEASE |
+            BinderDriverCommandProtocol::BC_DECREFS => {
+                let handle = read_struct::<u32>(w)?;
+                let strong = c == BinderDriverCommandProtocol::BC_RELEASE;
+                self.dec_ref(pid, handle, strong).unwrap_or_else(|e| {
+                    warn!("Mock process {}: {}", pid, e);
+                });
+            }
+            BinderDriverCommandProtocol::BC_INCREFS_DONE |
+            BinderDriverCommandProtocol::BC_ACQUIRE_DONE => {
+                let ptr = read_word(w)?;
+                read_word(w)?;
+                let strong = c == BinderDriverCommandProtocol::BC_ACQUIRE_DONE;
+                match self.process(pid).nodes.get(&ptr).cloned() {
+                    Some(node) => self.local_ref(node, strong, false),
+                    None => warn!("Mock process {} acknowledged unknown node 0x{:x}", pid, ptr),
+                }
+            }
+            BinderDriverCommandProtocol::BC_REGISTER_LOOPER => {
+                {
+                    let process = self.process(pid);
+                    if process.requested_threads > 0 {
+                        process.requested_threads -= 1;
+                        process.started_threads += 1;
+                    } else {
+                        warn!("Mock process {} registered a looper without request", pid);
+                    }
+                }
+                self.thread(pid, tid).looper = true;
+            }
+            BinderDriverCommandProtocol::BC_ENTER_LOOPER => {
+                self.thread(pid, tid).looper = true;
+            }
+            BinderDriverCommandProtocol::BC_EXIT_LOOPER => {
+                self.thread(pid, tid).looper = false;
+            }
+            BinderDriverCommandProtocol::BC_REQUEST_DEATH_NOTIFICATION => {
+                let handle = read_struct::<u32>(w)?;
+                let cookie = read_word(w)?;
+                let node = match self.process(pid).refs.get_mut(&handle) {
+                    Some(ref mut r) if r.death.is_none() => {
+                        r.death = Some(cookie);
+                        r.node
+                    }
+                    Some(_) => {
+                        warn!("Mock process {} requested death notification of handle {} twice", pid, handle);
+                        return Ok(());
+                    }
+                    None => {
+                        warn!("Mock process {} requested death notification of invalid handle {}", pid, handle);
+                        return Ok(());
+                    }
+                };
+                let owner = self.nodes[&node].owner;
+                if !self.processes.contains_key(&owner) {
+                    self.process(pid).todo.push_back(Work::DeadBinder(cookie));
+                }
+            }
+            BinderDriverCommandProtocol::BC_CLEAR_DEATH_NOTIFICATION => {
+                let handle = read_struct::<u32>(w)?;
+                let cookie = read_word(w)?;
+                match self.process(pid).refs.get_mut(&handle) {
+                    Some(ref mut r) if r.death == Some(cookie) => r.death = None,
+                    _ => {
+                        warn!("Mock process {} cleared unknown death notification of handle {}", pid, handle);
+                        return Ok(());
+                    }
+                }
+                self.thread(pid, tid).todo.push_back(Work::ClearDeathNotificationDone(cookie));
+            }
+            BinderDriverCommandProtocol::BC_DEAD_BINDER_DONE => {
+                read_word(w)?;
+            }
+            BinderDriverCommandProtocol::BC_ACQUIRE_RESULT |
+            BinderDriverCommandProtocol::BC_ATTEMPT_ACQUIRE => {
+                return Err(format!("Unsupported command {:?}", c).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn transaction(&mut self, pid: BinderPid, tid: ThreadId, td: &BinderTransactionData, reply: bool) {
+        let data = if td.data_size > 0 {
+            unsafe { from_raw_parts(td.data as *const u8, td.data_size as usize) }
+        } else {
+            &[]
+        };
+        // The offsets are read one by one since the sender doesn't align them.
+        let offsets = if td.offsets_size > 0 {
+            let mut d = unsafe { from_raw_parts(td.offsets as *const u8, td.offsets_size as usize) };
+            let mut offsets = vec![];
+            while let Ok(offset) = read_struct::<BinderSize>(&mut d) {
+                offsets.push(offset);
+            }
+            offsets
+        } else {
+            vec![]
+        };
+        let sender_pid = pid;
+
+        if reply {
+            let from = match self.thread(pid, tid).stack.pop() {
+                Some(Frame::Incoming(from)) => from,
+                _ => {
+                    warn!("Mock process {} replied without transaction", pid);
+                    self.thread(pid, tid).todo.push_back(Work::FailedReply);
+                    return;
+                }
+            };
+            self.thread(pid, tid).todo.push_back(Work::TransactionComplete);
+
+            // The caller might have died in the meantime.
+            let (to_pid, to_tid) = from;
+            let alive = self.processes.get(&to_pid).is_some_and(|p| p.threads.contains_key(&to_tid));
+            if !alive {
+                return;
+            }
+            let transaction = match self.copy_transaction((pid, tid), to_pid, td, data, &offsets) {
+                Ok(t) => Transaction { sender_pid: 0, ..t },
+                Err(e) => {
+                    warn!("Mock process {} sent invalid reply: {}", pid, e);
+                    self.dead_reply(from);
+                    return;
+                }
+            };
+            let thread = self.thread(to_pid, to_tid);
+            thread.stack.pop();
+            thread.todo.push_back(Work::Reply(transaction));
+            return;
+        }
+
+        let node = self.lookup(pid, td.target);
+        let (owner, ptr, cookie) = match node.and_then(|n| self.nodes.get(&n)) {
+            Some(n) => (n.owner, n.ptr, n.cookie),
+            None => {
+                warn!("Mock process {} used invalid handle {}", pid, td.target);
+                self.thread(pid, tid).todo.push_back(Work::FailedReply);
+                return;
+            }
+        };
+        if !self.processes.contains_key(&owner) {
+            self.thread(pid, tid).todo.push_back(Work::DeadReply);
+            return;
+        }
+
+        let one_way = (td.flags & TransactionFlags::ONE_WAY as u32) != 0;
+        let transaction = match self.copy_transaction((pid, tid), owner, td, data, &offsets) {
+            Ok(t) => Transaction {
+                sender_pid: if one_way { 0 } else { sender_pid },
+                ..t
+            },
+            Err(e) => {
+                warn!("Mock process {} sent invalid transaction: {}", pid, e);
+                self.thread(pid, tid).todo.push_back(Work::FailedReply);
+                return;
+            }
+        };
+
+        self.thread(pid, tid).todo.push_back(Work::TransactionComplete);
+        if one_way {
+            self.process(owner).todo.push_back(Work::Transaction {
+                ptr,
+                cookie,
+                transaction,
+                from: None,
+            });
+            return;
+        }
+
+        // Route nested calls back to the thread waiting for us.
+        let target_thread = self.thread(pid, tid)
+            .stack
+            .iter()
+            .rev()
+            .filter_map(|f| match *f {
+                Frame::Incoming((p, t)) if p == owner => Some(t),
+                _ => None,
+            })
+            .next();
+        self.thread(pid, tid).stack.push(Frame::Outgoing);
+        let work = Work::Transaction {
+            ptr,
+            cookie,
+            transaction,
+            from: Some((pid, tid)),
+        };
+        match target_thread {
+            Some(t) => self.thread(owner, t).todo.push_back(work),
+            None => self.process(owner).todo.push_back(work),
+        }
+    }
+
+    /// Copy the transaction into a buffer of process `to` and translate the
+    /// objects it contains.
+    fn copy_transaction(&mut self, from: ThreadRef, to: BinderPid, td: &BinderTransactionData, data: &[u8], offsets: &[BinderSize]) -> Result<Transaction> {
+        let mut data = data.to_vec();
+        let mut refs = vec![];
+        for offset in offsets {
+            if let Err(e) = self.translate(from, to, &mut data, *offset as usize, &mut refs) {
+                self.free_buffer(
+                    to,
+                    Buffer {
+                        _data: vec![],
+                        refs,
+                    },
+                );
+                return Err(e);
+            }
+        }
+        let (d, o) = self.alloc_buffer(to, &data, offsets, refs);
+        Ok(Transaction {
+            code: td.code,
+            flags: td.flags,
+            sender_pid: from.0,
+            sender_euid: self.process(from.0).euid,
+            data: d,
+            data_size: td.data_size,
+            offsets: o,
+            offsets_size: size_of_val(offsets) as BinderSize,
+        })
+    }
+
+    fn translate(&mut self, from: ThreadRef, to: BinderPid, data: &mut [u8], offset: usize, refs: &mut Vec<BufferRef>) -> Result<()> {
+        let size = FlatBinderObject::size(PROTOCOL);
+        if offset & 3 != 0 || offset + size > data.len() {
+            return Err(format!("Invalid object offset {}", offset).into());
+        }
+        let mut o = FlatBinderObject::decode(PROTOCOL, &mut &data[offset..])?;
+
+        let (node, strong) = match o.type_ {
+            t if t == BinderType::Binder as u32 || t == BinderType::WeakBinder as u32 => {
+                let node = match self.process(from.0).nodes.get(&o.handle_binder).cloned() {
+                    Some(node) => node,
+                    None => {
+                        let node = self.new_node(from.0, o.handle_binder, o.cookie);
+                        self.process(from.0).nodes.insert(o.handle_binder, node);
+                        node
+                    }
+                };
+                (node, t == BinderType::Binder as u32)
+            }
+            t if t == BinderType::Handle as u32 || t == BinderType::WeakHandle as u32 => {
+                let handle = o.handle_binder as u32;
+                let node = self.lookup(from.0, handle).ok_or_else(|| {
+                    Error::from(format!("Invalid handle {}", handle))
+                })?;
+                (node, t == BinderType::Handle as u32)
+            }
+            t => return Err(format!("Unsupported object type 0x{:x}", t).into()),
+        };
+
+        let (owner, ptr, cookie) = {
+            let n = &self.nodes[&node];
+            (n.owner, n.ptr, n.cookie)
+        };
+        if owner == to {
+            o.type_ = if strong { BinderType::Binder } else { BinderType::WeakBinder } as u32;
+            o.handle_binder = ptr;
+            o.cookie = cookie;
+            self.local_ref(node, strong, true);
+            refs.push(BufferRef::Local(node, strong));
+        } else {
+            let handle = self.inc_ref(to, node, strong, Some(from));
+            o.type_ = if strong { BinderType::Handle } else { BinderType::WeakHandle } as u32;
+            o.handle_binder = handle as BinderPtr;
+            o.cookie = 0;
+            refs.push(BufferRef::Handle(handle, strong));
+        }
+        let mut encoded = vec![];
+        o.encode(PROTOCOL, &mut encoded);
+        data[offset..offset + size].copy_from_slice(&encoded);
+        Ok(())
+    }
+
+    fn alloc_buffer(&mut self, pid: BinderPid, data: &[u8], offsets: &[BinderSize], refs: Vec<BufferRef>) -> (BinderPtr, BinderPtr) {
+        // Back the buffer with u64 to provide the alignment the kernel guarantees.
+        // The offsets are placed behind the data.
+        let words = data.len().div_ceil(size_of::<u64>());
+        let offsets_len = size_of_val(offsets);
+        let mut buffer = vec![0u64; words + offsets_len / size_of::<u64>() + 1];
+        unsafe {
+            let p = buffer.as_mut_ptr() as *mut u8;
+            ptr::copy_nonoverlapping(data.as_ptr(), p, data.len());
+            ptr::copy_nonoverlapping(offsets.as_ptr() as *const u8, p.add(words * size_of::<u64>()), offsets_len);
+        }
+        let p = buffer.as_ptr() as BinderPtr;
+        self.process(pid).buffers.insert(p, Buffer { _data: buffer, refs });
+        (p, p + (words * size_of::<u64>()) as BinderPtr)
+    }
+
+    /// Fail all transactions a released thread was supposed to reply to.
+    fn release_thread(&mut self, thread: Thread) {
+        for frame in thread.stack {
+            if let Frame::Incoming(from) = frame {
+                self.dead_reply(from);
+            }
+        }
+        for work in thread.todo {
+            self.release_work(work);
+        }
+    }
+
+    fn release_process(&mut self, pid: BinderPid) {
+        let process = match self.processes.remove(&pid) {
+            Some(p) => p,
+            None => return,
+        };
+        for (_, thread) in process.threads {
+            self.release_thread(thread);
+        }
+        for work in process.todo {
+            self.release_work(work);
+        }
+        for (_, r) in process.refs {
+            if let Some(n) = self.nodes.get_mut(&r.node) {
+                n.refs -= 1;
+                if r.strong > 0 {
+                    n.internal_strong -= 1;
+                }
+            }
+            self.update_node(r.node, None);
+        }
+        if self.context_mgr.is_some_and(|n| self.nodes[&n].owner == pid) {
+            self.context_mgr = None;
+        }
+        for (_, node) in process.nodes {
+            self.send_death(node);
+            self.update_node(node, None);
+        }
+    }
+
+    /// Notify the processes that requested it about the death of `node`.
+    fn send_death(&mut self, node: NodeId) {
+        for process in self.processes.values_mut() {
+            let deaths = process
+                .refs
+                .values()
+                .filter(|r| r.node == node)
+                .filter_map(|r| r.death)
+                .collect::<Vec<_>>();
+            for cookie in deaths {
+                process.todo.push_back(Work::DeadBinder(cookie));
+            }
+        }
+    }
+
+    fn release_work(&mut self, work: Work) {
+        if let Work::Transaction { from: Some(from), .. } = work {
+            self.dead_reply(from);
+        }
+    }
+
+    fn dead_reply(&mut self, (pid, tid): ThreadRef) {
+        if let Some(thread) = self.processes.get_mut(&pid).and_then(
+            |p| p.threads.get_mut(&tid),
+        )
+        {
+            thread.stack.pop();
+            thread.todo.push_back(Work::DeadReply);
+        }
+    }
+}
+
+fn read_word(w: &mut &[u8]) -> Result<BinderPtr> {
+    PROTOCOL.read_word(w).chain_err(|| "Invalid command")
+}
@@ -12,4 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-pub(crate) mod binder;
\ No newline at end of file
+pub(crate) mod binder;
+mod binderfs;
+mod buffer;
+mod builder;
+mod device;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+mod node;
+mod process_state;
+#[cfg(test)]
+pub(crate) mod tests;
+mod thread_state;
+mod transport;
+
+pub use self::binder::Binder;
+pub use self::binderfs::{BinderFs, BinderFsDevice};
+pub use self::buffer::Buffer;
+pub use self::builder::{BinderBuilder, BINDER_DEVICE, HWBINDER_DEVICE, VNDBINDER_DEVICE};
+pub use self::process_state::ProcessState;
+pub use self::transport::Transport;
+pub use types::BinderWriteRead;
@@ -0,0 +1,249 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use service::LocalBinder;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use types::BinderPtr;
+
+/// A local object published to the driver.
+///
+/// The driver asks for a strong reference while remote strong references
+/// exist and for a weak reference while remote references of any kind
+/// exist. Without a strong reference the object is only reachable as long
+/// as it is kept alive elsewhere in this process.
+struct Node {
+    object: Option<Arc<dyn LocalBinder>>,
+    weak: Weak<dyn LocalBinder>,
+    strong: usize,
+    weak_refs: usize,
+}
+
+/// The local objects known to the driver by cookie.
+#[derive(Default)]
+pub(crate) struct Nodes {
+    nodes: Mutex<HashMap<BinderPtr, Node>>,
+}
+
+impl Nodes {
+    /// Register `object` and return the cookie it is known as.
+    ///
+    /// Objects are kept alive until the driver released all references to
+    /// them. An object that never gets referenced stays registered until
+    /// it is `forget`ten.
+    pub fn register(&self, object: Arc<dyn LocalBinder>) -> BinderPtr {
+        let cookie = object.cookie();
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.entry(cookie).or_insert_with(|| {
+            Node {
+                object: None,
+                weak: Arc::downgrade(&object),
+                strong: 0,
+                weak_refs: 0,
+            }
+        });
+        if node.object.is_none() {
+            node.weak = Arc::downgrade(&object);
+            node.object = Some(object);
+        }
+        cookie
+    }
+
+    /// Unregister `cookie` unless the driver references it, e.g after the
+    /// transaction publishing it failed.
+    pub fn forget(&self, cookie: BinderPtr) {
+        let _node = {
+            let mut nodes = self.nodes.lock().unwrap();
+            let unused = nodes.get(&cookie).is_some_and(|n| n.strong == 0 && n.weak_refs == 0);
+            if unused { nodes.remove(&cookie) } else { None }
+        };
+    }
+
+    /// The object registered with `cookie` if it is still alive.
+    pub fn get(&self, cookie: BinderPtr) -> Option<Arc<dyn LocalBinder>> {
+        self.nodes.lock().unwrap().get(&cookie).and_then(|n| {
+            n.object.clone().or_else(|| n.weak.upgrade())
+        })
+    }
+
+    /// Handle `BR_INCREFS`.
+    pub fn inc_weak(&self, cookie: BinderPtr) {
+        match self.nodes.lock().unwrap().get_mut(&cookie) {
+            Some(node) => node.weak_refs += 1,
+            None => warn!("Weak reference to unknown object 0x{:x}", cookie),
+        }
+    }
+
+    /// Handle `BR_ACQUIRE`.
+    pub fn inc_strong(&self, cookie: BinderPtr) {
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get_mut(&cookie) {
+            Some(node) => {
+                node.strong += 1;
+                if node.object.is_none() {
+                    node.object = node.weak.upgrade();
+                }
+            }
+            None => warn!("Strong reference to unknown object 0x{:x}", cookie),
+        }
+    }
+
+    /// Handle `BR_RELEASE`.
+    pub fn dec_strong(&self, cookie: BinderPtr) {
+        // Drop the object outside of the lock as it may hold further objects.
+        let _object = {
+            let mut nodes = self.nodes.lock().unwrap();
+            match nodes.get_mut(&cookie) {
+                Some(ref mut node) if node.strong > 0 => {
+                    node.strong -= 1;
+                    if node.strong == 0 { node.object.take() } else { None }
+                }
+                _ => {
+                    warn!("Release of unreferenced object 0x{:x}", cookie);
+                    None
+                }
+            }
+        };
+    }
+
+    /// Handle `BR_DECREFS`.
+    pub fn dec_weak(&self, cookie: BinderPtr) {
+        let _node = {
+            let mut nodes = self.nodes.lock().unwrap();
+            let unused = match nodes.get_mut(&cookie) {
+                Some(ref mut node) if node.weak_refs > 0 => {
+                    node.weak_refs -= 1;
+                    node.weak_refs == 0 && node.strong == 0
+                }
+                _ => {
+                    warn!("Weak release of unreferenced object 0x{:x}", cookie);
+                    false
+                }
+            };
+            if unused { nodes.remove(&cookie) } else { None }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use errors::*;
+    use service::{LocalBinder, Parcel};
+    use std::sync::Arc;
+    use super::Nodes;
+
+    struct Object;
+
+    impl LocalBinder for Object {
+        fn on_transact(&self, _code: u32, _data: &mut Parcel, _reply: &mut Parcel, _flags: u32) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn registered(nodes: &Nodes) -> usize {
+        nodes.nodes.lock().unwrap().len()
+    }
+
+    #[test]
+    fn strong_references_keep_objects_alive() {
+        let nodes = Nodes::default();
+        let object: Arc<dyn LocalBinder> = Arc::new(Object);
+        let weak = Arc::downgrade(&object);
+        let cookie = nodes.register(object);
+
+        nodes.inc_weak(cookie);
+        nodes.inc_strong(cookie);
+        nodes.inc_strong(cookie);
+        nodes.dec_strong(cookie);
+        assert!(weak.upgrade().is_some());
+        assert!(nodes.get(cookie).is_some());
+
+        nodes.dec_strong(cookie);
+        assert!(weak.upgrade().is_none());
+        assert!(nodes.get(cookie).is_none());
+        assert_eq!(registered(&nodes), 1);
+
+        nodes.dec_weak(cookie);
+        assert_eq!(registered(&nodes), 0);
+    }
+
+    #[test]
+    fn weak_references_keep_nodes() {
+        let nodes = Nodes::default();
+        let object: Arc<dyn LocalBinder> = Arc::new(Object);
+        let cookie = nodes.register(object.clone());
+
+        nodes.inc_weak(cookie);
+        nodes.inc_strong(cookie);
+        nodes.dec_strong(cookie);
+        // Still reachable while alive elsewhere and held again when acquired.
+        assert!(nodes.get(cookie).is_some());
+        nodes.inc_strong(cookie);
+        drop(object);
+        assert!(nodes.get(cookie).is_some());
+        nodes.dec_strong(cookie);
+        assert!(nodes.get(cookie).is_none());
+
+        nodes.dec_weak(cookie);
+        assert_eq!(registered(&nodes), 0);
+    }
+
+    #[test]
+    fn registration_holds_objects_until_released() {
+        let nodes = Nodes::default();
+        let object: Arc<dyn LocalBinder> = Arc::new(Object);
+        let weak = Arc::downgrade(&object);
+        let cookie = nodes.register(object);
+        assert_eq!(nodes.register(weak.upgrade().unwrap()), cookie);
+        assert!(weak.upgrade().is_some());
+
+        nodes.inc_weak(cookie);
+        nodes.dec_weak(cookie);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(registered(&nodes), 0);
+    }
+
+    #[test]
+    fn only_unreferenced_objects_are_forgotten() {
+        let nodes = Nodes::default();
+        let object: Arc<dyn LocalBinder> = Arc::new(Object);
+        let weak = Arc::downgrade(&object);
+        let cookie = nodes.register(object);
+        nodes.inc_weak(cookie);
+        nodes.forget(cookie);
+        assert!(nodes.get(cookie).is_some());
+        nodes.inc_strong(cookie);
+        nodes.forget(cookie);
+        nodes.dec_strong(cookie);
+        nodes.dec_weak(cookie);
+
+        let cookie = nodes.register(Arc::new(Object));
+        nodes.forget(cookie);
+        nodes.forget(0x1234);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(registered(&nodes), 0);
+    }
+
+    #[test]
+    fn unbalanced_releases_are_ignored() {
+        let nodes = Nodes::default();
+        let cookie = nodes.register(Arc::new(Object));
+        nodes.dec_strong(cookie);
+        nodes.dec_weak(cookie);
+        nodes.dec_strong(0x1234);
+        nodes.inc_weak(0x1234);
+        assert!(nodes.get(cookie).is_some());
+        assert_eq!(registered(&nodes), 1);
+    }
+}
@@ -0,0 +1,65 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use errors::*;
+use std::sync::{Arc, Mutex};
+use super::{Binder, BinderBuilder, BINDER_DEVICE};
+
+/// The opened binder contexts by device path.
+static CONTEXTS: Mutex<Vec<(String, Arc<Binder>)>> = Mutex::new(Vec::new());
+
+/// The binder state of this process, like libbinder's `ProcessState`.
+///
+/// Each device is opened and mapped once and the resulting `Binder` is
+/// shared by every `ServiceManager`, `Service` and proxy obtained from it
+/// for the lifetime of the process. Contexts of different devices, e.g
+/// `/dev/binder` and `/dev/vndbinder`, are independent of each other.
+pub struct ProcessState;
+
+impl ProcessState {
+    /// The binder of the default device. It is opened on first use.
+    pub fn binder() -> Result<Arc<Binder>> {
+        ProcessState::context(BINDER_DEVICE)
+    }
+
+    /// The binder of `device`. It is opened with the default configuration
+    /// on first use.
+    pub fn context(device: &str) -> Result<Arc<Binder>> {
+        let mut contexts = CONTEXTS.lock().unwrap();
+        if let Some((_, binder)) = contexts.iter().find(|(d, _)| d == device) {
+            return Ok(binder.clone());
+        }
+        let binder = Arc::new(BinderBuilder::new().device(device).build()?);
+        contexts.push((device.to_owned(), binder.clone()));
+        Ok(binder)
+    }
+
+    /// Use `binder` as binder of the default device, e.g one attached to a
+    /// `MockDriver`. Fails if the default context is already initialized.
+    pub fn init(binder: Binder) -> Result<Arc<Binder>> {
+        ProcessState::init_context(BINDER_DEVICE, binder)
+    }
+
+    /// Use `binder` as binder of `device`, e.g one built with a custom
+    /// `BinderBuilder` configuration. Fails if the context is already initialized.
+    pub fn init_context(device: &str, binder: Binder) -> Result<Arc<Binder>> {
+        let mut contexts = CONTEXTS.lock().unwrap();
+        if contexts.iter().any(|(d, _)| d == device) {
+            return Err(format!("Context {} is already initialized", device).into());
+        }
+        let binder = Arc::new(binder);
+        contexts.push((device.to_owned(), binder.clone()));
+        Ok(binder)
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Tests of the binder layer against the mock driver.
+
+use errors::*;
+use service::{DeathRecipient, LocalBinder, Object, Parcel, RemoteBinder};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::Binder;
+use super::binder::{CallResult, Reply};
+use super::mock::MockDriver;
+use types::{Transaction, TransactionFlags};
+
+/// Doubles the `i32` sent with code 1.
+pub(crate) struct Echo;
+
+impl LocalBinder for Echo {
+    fn on_transact(&self, code: u32, data: &mut Parcel, reply: &mut Parcel, _flags: u32) -> Result<()> {
+        match code {
+            1 => reply.put_i32(data.get_i32()? * 2),
+            _ => Err(StatusCode::UnknownTransaction.into()),
+        }
+    }
+}
+
+/// Forwards the `i32` sent with code 1 and the flags of the transaction.
+struct Forward(Mutex<Sender<(i32, u32)>>);
+
+impl LocalBinder for Forward {
+    fn on_transact(&self, code: u32, data: &mut Parcel, _reply: &mut Parcel, flags: u32) -> Result<()> {
+        match code {
+            1 => {
+                self.0.lock().unwrap().send((data.get_i32()?, flags)).unwrap();
+                Ok(())
+            }
+            _ => Err(StatusCode::UnknownTransaction.into()),
+        }
+    }
+}
+
+/// Sends the handle of the died objects.
+struct Obituary(Mutex<Sender<u32>>);
+
+impl DeathRecipient for Obituary {
+    fn binder_died(&self, who: &RemoteBinder) {
+        self.0.lock().unwrap().send(who.handle()).unwrap();
+    }
+}
+
+/// Keeps the object sent with code 1 or the list of objects sent with code 3
+/// and drops them on code 2.
+#[derive(Default)]
+struct Holder {
+    binder: Mutex<Weak<Binder>>,
+    held: Mutex<Vec<RemoteBinder>>,
+}
+
+impl LocalBinder for Holder {
+    fn on_transact(&self, code: u32, data: &mut Parcel, _reply: &mut Parcel, _flags: u32) -> Result<()> {
+        match code {
+            1 => {
+                let binder = self.binder.lock().unwrap().upgrade().unwrap();
+                match data.get_obj()? {
+                    Object::Handle(handle) => self.held.lock().unwrap().push(RemoteBinder::new(&binder, handle)?),
+                    _ => return Err(StatusCode::BadType.into()),
+                }
+            }
+            2 => self.held.lock().unwrap().clear(),
+            3 => {
+                let binder = self.binder.lock().unwrap().upgrade().unwrap();
+                for object in data.get::<Vec<Object>>()? {
+                    match object {
+                        Object::Handle(handle) => self.held.lock().unwrap().push(RemoteBinder::new(&binder, handle)?),
+                        _ => return Err(StatusCode::BadType.into()),
+                    }
+                }
+            }
+            _ => return Err(StatusCode::UnknownTransaction.into()),
+        }
+        Ok(())
+    }
+}
+
+/// Open a process of `driver` serving `object` as context manager.
+pub(crate) fn context_manager(driver: &MockDriver, object: Arc<dyn LocalBinder>) -> Arc<Binder> {
+    let binder = Arc::new(Binder::with_transport(Box::new(driver.open())).unwrap());
+    binder.become_context_manager(object).unwrap();
+    Binder::start_thread_pool(&binder).unwrap();
+    binder
+}
+
+/// Open a process of `driver` serving its objects.
+pub(crate) fn server(driver: &MockDriver) -> Arc<Binder> {
+    let binder = Arc::new(Binder::with_transport(Box::new(driver.open())).unwrap());
+    Binder::start_thread_pool(&binder).unwrap();
+    binder
+}
+
+/// Wait a few seconds for `condition` to become true.
+pub(crate) fn eventually<F: Fn() -> bool>(condition: F) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+pub(crate) fn i32_parcel(n: i32) -> Parcel {
+    let mut parcel = Parcel::default();
+    parcel.put_i32(n).unwrap();
+    parcel
+}
+
+#[test]
+fn calls_are_replied() {
+    let driver = MockDriver::new();
+    let _manager = context_manager(&driver, Arc::new(Echo));
+    let client = Binder::with_transport(Box::new(driver.open())).unwrap();
+    match client.call(&i32_parcel(21), 0, 1, 0).unwrap() {
+        CallResult::Reply(Reply::Data(mut reply)) => {
+            assert_eq!(reply.get_i32().unwrap(), 42);
+            assert_eq!(reply.data_avail(), 0);
+        }
+        r => panic!("Unexpected {:?}", r),
+    }
+    match client.call(&Parcel::default(), 0, Transaction::Ping as u32, 0).unwrap() {
+        CallResult::Reply(Reply::Data(reply)) => assert!(reply.is_empty()),
+        r => panic!("Unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn failures_are_replied_as_status_codes() {
+    let driver = MockDriver::new();
+    let _manager = context_manager(&driver, Arc::new(Echo));
+    let client = Binder::with_transport(Box::new(driver.open())).unwrap();
+    match client.call(&i32_parcel(21), 0, 2, 0).unwrap() {
+        CallResult::Reply(Reply::StatusCode(c)) => assert_eq!(c as i32, StatusCode::UnknownTransaction as i32),
+        r => panic!("Unexpected {:?}", r),
+    }
+    // A parcel too short for the request.
+    match client.call(&Parcel::default(), 0, 1, 0).unwrap() {
+        CallResult::Reply(Reply::StatusCode(c)) => assert_ne!(c, 0),
+        r => panic!("Unexpected {:?}", r),
+    }
+    // The looper survives the failures.
+    match client.call(&i32_parcel(1), 0, 1, 0).unwrap() {
+        CallResult::Reply(Reply::Data(mut reply)) => assert_eq!(reply.get_i32().unwrap(), 2),
+        r => panic!("Unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn one_way_calls_are_not_replied() {
+    let driver = MockDriver::new();
+    let (tx, rx) = channel();
+    let _manager = context_manager(&driver, Arc::new(Forward(Mutex::new(tx))));
+    let client = Binder::with_transport(Box::new(driver.open())).unwrap();
+    let one_way = TransactionFlags::ONE_WAY as u32;
+    for n in 0..3 {
+        match client.call(&i32_parcel(n), 0, 1, one_way).unwrap() {
+            CallResult::Noop => (),
+            r => panic!("Unexpected {:?}", r),
+        }
+    }
+    // One way calls to an object are delivered in order.
+    for n in 0..3 {
+        let (m, flags) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(m, n);
+        assert_ne!(flags & one_way, 0);
+    }
+    // Failures aren't reported to the caller.
+    match client.call(&Parcel::default(), 0, 2, one_way).unwrap() {
+        CallResult::Noop => (),
+        r => panic!("Unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn death_of_a_process_is_notified() {
+    let driver = MockDriver::new();
+    let holder = Arc::new(Holder::default());
+    let manager = context_manager(&driver, holder.clone());
+    *holder.binder.lock().unwrap() = Arc::downgrade(&manager);
+
+    // Without a thread pool the process is released with the binder.
+    let server = Binder::with_transport(Box::new(driver.open())).unwrap();
+    let mut parcel = Parcel::default();
+    parcel.put_binder(Arc::new(Echo)).unwrap();
+    server.call(&parcel, 0, 1, 0).unwrap();
+    let remote = holder.held.lock().unwrap()[0].clone();
+
+    let (tx, rx) = channel();
+    let linked: Arc<dyn DeathRecipient> = Arc::new(Obituary(Mutex::new(tx.clone())));
+    let unlinked: Arc<dyn DeathRecipient> = Arc::new(Obituary(Mutex::new(tx)));
+    remote.link_to_death(linked.clone()).unwrap();
+    remote.link_to_death(unlinked.clone()).unwrap();
+    remote.unlink_to_death(&unlinked).unwrap();
+    assert!(remote.unlink_to_death(&unlinked).is_err());
+
+    drop(server);
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), remote.handle());
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    match *remote.transact(1, &i32_parcel(1), 0).unwrap_err().kind() {
+        ErrorKind::DeadReply => (),
+        ref e => panic!("Unexpected {:?}", e),
+    }
+    assert!(remote.link_to_death(linked).is_err());
+
+    // Objects sent to the dead process aren't kept.
+    let object: Arc<dyn LocalBinder> = Arc::new(Echo);
+    let weak = Arc::downgrade(&object);
+    let mut parcel = Parcel::default();
+    parcel.put_binder(object).unwrap();
+    assert!(remote.transact(1, &parcel, 0).is_err());
+    drop(parcel);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn transaction_buffers_are_freed() {
+    let driver = MockDriver::new();
+    let manager_process = Arc::new(driver.open());
+    let manager = Arc::new(Binder::with_transport(Box::new(manager_process.clone())).unwrap());
+    manager.become_context_manager(Arc::new(Echo)).unwrap();
+    Binder::start_thread_pool(&manager).unwrap();
+    let client_process = Arc::new(driver.open());
+    let client = Binder::with_transport(Box::new(client_process.clone())).unwrap();
+
+    for n in 0..50 {
+        client.call(&i32_parcel(n), 0, 1, 0).unwrap();
+        assert_eq!(client_process.allocated_buffers(), 0);
+        client.call(&i32_parcel(n), 0, 2, 0).unwrap();
+        assert_eq!(client_process.allocated_buffers(), 0);
+        client.call(&i32_parcel(n), 0, 1, TransactionFlags::ONE_WAY as u32).unwrap();
+    }
+    assert!(eventually(|| manager_process.allocated_buffers() == 0));
+}
+
+#[test]
+fn remote_references_keep_local_objects_alive() {
+    let driver = MockDriver::new();
+    let holder = Arc::new(Holder::default());
+    let manager = context_manager(&driver, holder.clone());
+    *holder.binder.lock().unwrap() = Arc::downgrade(&manager);
+
+    let client = server(&driver);
+    let object: Arc<dyn LocalBinder> = Arc::new(Echo);
+    let weak = Arc::downgrade(&object);
+    let mut parcel = Parcel::default();
+    parcel.put_binder(object).unwrap();
+    client.call(&parcel, 0, 1, 0).unwrap();
+    drop(parcel);
+    assert_eq!(holder.held.lock().unwrap().len(), 1);
+    assert!(weak.upgrade().is_some());
+
+    // The remote proxy answers while the reference is held.
+    let remote = holder.held.lock().unwrap()[0].clone();
+    let mut parcel = Parcel::default();
+    parcel.put_i32(21).unwrap();
+    match remote.transact(1, &parcel, 0).unwrap() {
+        CallResult::Reply(Reply::Data(mut reply)) => assert_eq!(reply.get_i32().unwrap(), 42),
+        r => panic!("Unexpected {:?}", r),
+    }
+    drop(remote);
+
+    client.call(&Parcel::default(), 0, 2, 0).unwrap();
+    assert!(eventually(|| weak.upgrade().is_none()));
+}
+
+#[test]
+fn binder_lists_are_translated() {
+    let driver = MockDriver::new();
+    let holder = Arc::new(Holder::default());
+    let manager = context_manager(&driver, holder.clone());
+    *holder.binder.lock().unwrap() = Arc::downgrade(&manager);
+
+    let client = server(&driver);
+    let objects: Vec<Object> = (0..3).map(|_| Object::Local(Arc::new(Echo))).collect();
+    let mut parcel = Parcel::default();
+    parcel.put(&objects).unwrap();
+    client.call(&parcel, 0, 3, 0).unwrap();
+
+    let held = holder.held.lock().unwrap().clone();
+    assert_eq!(held.len(), 3);
+    for (n, remote) in held.iter().enumerate() {
+        match remote.transact(1, &i32_parcel(n as i32), 0).unwrap() {
+            CallResult::Reply(Reply::Data(mut reply)) => assert_eq!(reply.get_i32().unwrap(), 2 * n as i32),
+            r => panic!("Unexpected {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use errors::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use super::transport::Transport;
+use types::*;
+
+const READ_SIZE: usize = 256;
+
+/// The driver communication state of a thread, like libbinder's `IPCThreadState`.
+///
+/// Commands are collected in `output` and written with the next read. Data
+/// read from the driver is kept in `input` until every command in it is
+/// processed, even if the thread returns to the caller in between.
+#[derive(Default)]
+pub(crate) struct ThreadState {
+    pub input: Vec<u8>,
+    pub position: usize,
+    pub output: Vec<u8>,
+    /// Whether the thread serves the thread pool.
+    pub looper: bool,
+    /// Depth of commands being executed, e.g nested incoming transactions.
+    pub executing: usize,
+    /// Sender pid and euid of the incoming transaction being handled.
+    pub calling: Option<(BinderPid, BinderUid)>,
+}
+
+thread_local! {
+    /// States of the current thread by binder id.
+    static STATES: RefCell<HashMap<usize, ThreadState>> = RefCell::new(HashMap::new());
+}
+
+impl ThreadState {
+    /// Run `f` on the state of the current thread for binder `id`.
+    ///
+    /// `f` must not call back into binder since the states are borrowed.
+    pub fn with<F, R>(id: usize, f: F) -> R
+    where
+        F: FnOnce(&mut ThreadState) -> R,
+    {
+        STATES.with(|s| f(s.borrow_mut().entry(id).or_default()))
+    }
+
+    /// Drop the state of the current thread for binder `id`.
+    pub fn remove(id: usize) {
+        STATES.with(|s| s.borrow_mut().remove(&id));
+    }
+
+    /// Write the queued commands and, if `read` is set and all read
+    /// commands are processed, read new ones.
+    ///
+    /// Like libbinder nothing is written while unprocessed commands are
+    /// pending in a read, so responses aren't mixed up with older commands.
+    pub fn talk(id: usize, transport: &dyn Transport, read: bool) -> Result<()> {
+        ThreadState::with(id, |t| {
+            let need_read = t.consumed();
+            let write = if !read || need_read { t.output.len() } else { 0 };
+            let read = read && need_read;
+            if write == 0 && !read {
+                return Ok(());
+            }
+            if read {
+                t.input.resize(READ_SIZE, 0);
+            }
+
+            let mut bwr = BinderWriteRead {
+                write_size: write as BinderSize,
+                write_consumed: 0,
+                write_buffer: t.output.as_ptr() as BinderPtr,
+                read_size: if read { READ_SIZE as BinderSize } else { 0 },
+                read_consumed: 0,
+                read_buffer: t.input.as_mut_ptr() as BinderPtr,
+            };
+            if let Err(e) = transport.write_read(&mut bwr) {
+                // The queued commands may refer to data that is gone once
+                // the caller sees the error.
+                t.output.clear();
+                if read {
+                    t.input.clear();
+                    t.position = 0;
+                }
+                return Err(e);
+            }
+
+            t.output.drain(..bwr.write_consumed as usize);
+            if read {
+                t.input.truncate(bwr.read_consumed as usize);
+                t.position = 0;
+                debug!("Read data:");
+                hex!(&t.input);
+            }
+            Ok(())
+        })
+    }
+
+    /// Whether queued commands should be written right away instead of with
+    /// the next read, i.e the thread isn't about to talk to the driver anyway.
+    pub fn flush_needed(id: usize) -> bool {
+        ThreadState::with(id, |t| !t.looper && t.executing == 0)
+    }
+
+    /// Whether all read commands are processed.
+    pub fn consumed(&self) -> bool {
+        self.position >= self.input.len()
+    }
+
+    /// Take the next read command and its payload encoded in `protocol`.
+    pub fn next_command(&mut self, protocol: Protocol) -> Result<Option<(BinderDriverReturnProtocol, Vec<u8>)>> {
+        if self.consumed() {
+            return Ok(None);
+        }
+        let mut d = &self.input[self.position..];
+        let code = d.read_u32::<LittleEndian>().chain_err(
+            || "Invalid read reply",
+        )?;
+        let c = match BinderDriverReturnProtocol::from_code(code, protocol) {
+            Some(c) => c,
+            None => {
+                self.position = self.input.len();
+                return Err(format!("Unknown return command 0x{:x}", code).into());
+            }
+        };
+        let size = c.payload_size(protocol);
+        if d.len() < size {
+            self.position = self.input.len();
+            return Err(format!("Truncated {:?}: {} of {} bytes", c, d.len(), size).into());
+        }
+        let payload = d[..size].to_vec();
+        self.position = self.input.len() - d.len() + size;
+        Ok(Some((c, payload)))
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use errors::*;
+use std::sync::Arc;
+use types::BinderWriteRead;
+
+/// Access to a binder driver instance.
+///
+/// `Binder` talks to the driver exclusively through this trait. The kernel
+/// implementation issues the corresponding ioctls on an opened device while
+/// `MockDriver` processes the command and return streams in process.
+pub trait Transport: Send + Sync {
+    /// Query the protocol version spoken by the driver (`BINDER_VERSION`).
+    fn version(&self) -> Result<i32>;
+
+    /// Consume the write buffer and fill the read buffer of `bwr` (`BINDER_WRITE_READ`).
+    fn write_read(&self, bwr: &mut BinderWriteRead) -> Result<()>;
+
+    /// Set the maximum number of looper threads the driver may request (`BINDER_SET_MAX_THREADS`).
+    fn set_max_threads(&self, threads: u32) -> Result<()>;
+
+    /// Become the context manager, i.e the target of handle 0 (`BINDER_SET_CONTEXT_MGR`).
+    fn set_context_mgr(&self) -> Result<()>;
+
+    /// Release the driver state of the calling thread (`BINDER_THREAD_EXIT`).
+    fn thread_exit(&self) -> Result<()>;
+}
+
+/// Share a transport, e.g a `MockProcess` that is inspected while in use.
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    fn version(&self) -> Result<i32> {
+        (**self).version()
+    }
+
+    fn write_read(&self, bwr: &mut BinderWriteRead) -> Result<()> {
+        (**self).write_read(bwr)
+    }
+
+    fn set_max_threads(&self, threads: u32) -> Result<()> {
+        (**self).set_max_threads(threads)
+    }
+
+    fn set_context_mgr(&self) -> Result<()> {
+        (**self).set_context_mgr()
+    }
+
+    fn thread_exit(&self) -> Result<()> {
+        (**self).thread_exit()
+    }
+}
@@ -12,4 +12,83 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-error_chain!{}
+error_chain!{
+    errors {
+        Status(status: i32) {
+            description("Transaction failed with status code")
+            display("Transaction failed with status code {}", status)
+        }
+        DeadReply {
+            description("Target of the transaction is dead")
+            display("Target of the transaction is dead")
+        }
+        Exception(code: i32, message: String) {
+            description("Service replied with an exception")
+            display("Service replied with exception {}: {}", code, message)
+        }
+        ServiceSpecific(code: i32, message: String) {
+            description("Service replied with a service specific error")
+            display("Service replied with service specific error {}: {}", code, message)
+        }
+    }
+}
+
+/// Status codes used by libbinder (`status_t`).
+#[repr(i32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatusCode {
+    Ok = 0,
+    UnknownError = i32::MIN,
+    NoMemory = -12,
+    InvalidOperation = -38,
+    BadValue = -22,
+    BadType = i32::MIN + 1,
+    NameNotFound = -2,
+    PermissionDenied = -1,
+    NoInit = -19,
+    AlreadyExists = -17,
+    DeadObject = -32,
+    FailedTransaction = i32::MIN + 2,
+    BadIndex = -75,
+    NotEnoughData = -61,
+    WouldBlock = -11,
+    TimedOut = -110,
+    UnknownTransaction = -74,
+    FdsNotAllowed = i32::MIN + 7,
+    UnexpectedNull = i32::MIN + 8,
+}
+
+/// Exception codes of the AIDL status header in replies (`binder::Status`).
+#[repr(i32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExceptionCode {
+    None = 0,
+    Security = -1,
+    BadParcelable = -2,
+    IllegalArgument = -3,
+    NullPointer = -4,
+    IllegalState = -5,
+    NetworkMainThread = -6,
+    UnsupportedOperation = -7,
+    ServiceSpecific = -8,
+    Parcelable = -9,
+    HasReplyHeader = -128,
+    TransactionFailed = -129,
+}
+
+impl From<StatusCode> for Error {
+    fn from(s: StatusCode) -> Error {
+        ErrorKind::Status(s as i32).into()
+    }
+}
+
+impl Error {
+    /// The status code to report for this error in a reply.
+    pub fn status(&self) -> i32 {
+        match *self.kind() {
+            ErrorKind::Status(s) => s,
+            ErrorKind::DeadReply => StatusCode::DeadObject as i32,
+            _ => StatusCode::UnknownError as i32,
+        }
+    }
+}
@@ -13,6 +13,7 @@
 // limitations under the License.
 //
 
+extern crate android_binder_derive;
 extern crate byteorder;
 #[macro_use]
 extern crate error_chain;
@@ -24,6 +25,6 @@ extern crate nix;
 #[macro_use]
 mod utils;
 mod types;
-mod binder;
+pub mod binder;
 pub mod service;
 pub mod errors;
@@ -0,0 +1,479 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use errors::*;
+use std::process;
+use std::sync::{Arc, Mutex, Weak};
+use super::{LocalBinder, Object, Parcel, ServiceManager};
+use types::BinderPtr;
+
+const INTERFACE_CLIENT_CALLBACK: &str = "android.os.IClientCallback";
+const ON_CLIENTS: u32 = 1;
+
+type ActiveServicesCallback = Box<dyn Fn(bool) -> bool + Send + Sync>;
+type Exit = Box<dyn Fn() + Send + Sync>;
+
+struct Registration {
+    name: String,
+    object: Arc<dyn LocalBinder>,
+    allow_isolated: bool,
+    dump_priority: i32,
+    has_clients: bool,
+    registered: bool,
+}
+
+#[derive(Default)]
+struct State {
+    services: Vec<Registration>,
+    force_persist: bool,
+    /// Whether any service had clients when last checked.
+    active: bool,
+}
+
+/// Publishes lazy services that exit when none of them has clients.
+///
+/// The service manager tells the registrar via an `IClientCallback` when a
+/// service gains or loses its clients. Once no service has clients, all are
+/// unregistered and the process exits, to be started again by init when the
+/// next client asks for one of them. If a service gains a client before it
+/// is unregistered, the others are registered again and the process keeps
+/// running. Only supported by the AIDL service manager. Notifications need
+/// a looper thread, see `Binder::start_thread_pool`, and are ignored once
+/// the registrar and its clones are dropped.
+#[derive(Clone)]
+pub struct LazyServiceRegistrar {
+    counter: Arc<ClientCounter>,
+}
+
+struct ClientCounter {
+    service_manager: ServiceManager,
+    state: Mutex<State>,
+    callback: Mutex<Option<ActiveServicesCallback>>,
+    client_callback: Arc<ClientCallback>,
+    /// Called once all services are unregistered for lack of clients.
+    exit: Exit,
+}
+
+/// The `IClientCallback` passed to the service manager.
+struct ClientCallback {
+    counter: Weak<ClientCounter>,
+}
+
+impl LazyServiceRegistrar {
+    pub fn new(service_manager: ServiceManager) -> LazyServiceRegistrar {
+        LazyServiceRegistrar::with_exit(service_manager, Box::new(|| process::exit(0)))
+    }
+
+    /// Like `new` but call `exit` instead of exiting the process.
+    fn with_exit(service_manager: ServiceManager, exit: Exit) -> LazyServiceRegistrar {
+        LazyServiceRegistrar {
+            counter: Arc::new_cyclic(|counter| ClientCounter {
+                service_manager,
+                state: Mutex::new(State::default()),
+                callback: Mutex::new(None),
+                client_callback: Arc::new(ClientCallback { counter: counter.clone() }),
+                exit,
+            }),
+        }
+    }
+
+    /// Publish `object` under `name` like `ServiceManager::add_service` and
+    /// track its clients.
+    pub fn register_service(&self, name: &str, object: Arc<dyn LocalBinder>, allow_isolated: bool, dump_priority: i32) -> Result<()> {
+        let mut state = self.counter.state.lock().unwrap();
+        let registration = Registration {
+            name: name.to_owned(),
+            object,
+            allow_isolated,
+            dump_priority,
+            has_clients: false,
+            registered: false,
+        };
+        self.counter.register(&registration)?;
+        state.services.push(Registration {
+            registered: true,
+            ..registration
+        });
+        Ok(())
+    }
+
+    /// Keep the process running while `persist` is set, even without clients.
+    pub fn force_persist(&self, persist: bool) {
+        self.counter.state.lock().unwrap().force_persist = persist;
+        if !persist {
+            // The clients may have gone while persisting
+            self.counter.update();
+        }
+    }
+
+    /// Call `callback` with whether any service has clients when that changes,
+    /// instead of exiting when none has. The registrar doesn't exit if the
+    /// callback returns `true`, it may call `try_unregister` and
+    /// `re_register` to do so itself.
+    pub fn set_active_services_callback<F>(&self, callback: F)
+    where
+        F: Fn(bool) -> bool + Send + Sync + 'static,
+    {
+        *self.counter.callback.lock().unwrap() = Some(Box::new(callback));
+    }
+
+    /// Unregister all services. Fails if any of them has clients, in which
+    /// case the ones unregistered so far stay unregistered.
+    pub fn try_unregister(&self) -> Result<()> {
+        self.counter.try_unregister(&mut self.counter.state.lock().unwrap())
+    }
+
+    /// Register the services unregistered with `try_unregister` again.
+    pub fn re_register(&self) -> Result<()> {
+        self.counter.re_register(&mut self.counter.state.lock().unwrap())
+    }
+}
+
+impl ClientCounter {
+    fn register(&self, registration: &Registration) -> Result<()> {
+        let sm = &self.service_manager;
+        sm.add_service(
+            &registration.name,
+            registration.object.clone(),
+            registration.allow_isolated,
+            registration.dump_priority,
+        )?;
+        sm.register_client_callback(&registration.name, registration.object.clone(), self.client_callback.clone())
+    }
+
+    fn try_unregister(&self, state: &mut State) -> Result<()> {
+        for registration in state.services.iter_mut().filter(|r| r.registered) {
+            self.service_manager
+                .try_unregister_service(&registration.name, registration.object.clone())
+                .chain_err(|| format!("Failed to unregister {}", registration.name))?;
+            info!("Unregistered service {}", registration.name);
+            registration.registered = false;
+        }
+        Ok(())
+    }
+
+    fn re_register(&self, state: &mut State) -> Result<()> {
+        for registration in state.services.iter_mut().filter(|r| !r.registered) {
+            self.register(registration)?;
+            info!("Registered service {} again", registration.name);
+            registration.registered = true;
+        }
+        Ok(())
+    }
+
+    /// Set whether the service with `cookie` has clients.
+    fn on_clients(&self, cookie: BinderPtr, has_clients: bool) {
+        {
+            let mut state = self.state.lock().unwrap();
+            match state.services.iter_mut().find(|r| r.object.cookie() == cookie) {
+                Some(registration) => {
+                    info!("Service {} has clients: {}", registration.name, has_clients);
+                    registration.has_clients = has_clients;
+                }
+                None => {
+                    warn!("Client notification for unknown service 0x{:x}", cookie);
+                    return;
+                }
+            }
+        }
+        self.update();
+    }
+
+    /// Report a change of the services having clients or exit if none has.
+    fn update(&self) {
+        if self.unregister_if_unused() {
+            info!("Exiting since no service has clients");
+            (self.exit)();
+        }
+    }
+
+    /// Unregister all services if none has clients, no callback handles
+    /// that and the process isn't forced to persist. Whether all services
+    /// were unregistered, i.e the process should exit.
+    fn unregister_if_unused(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let active = state.services.iter().any(|r| r.has_clients);
+        let changed = active != state.active;
+        state.active = active;
+
+        if let Some(ref callback) = *self.callback.lock().unwrap() {
+            if changed {
+                drop(state);
+                if callback(active) {
+                    return false;
+                }
+                state = self.state.lock().unwrap();
+            } else {
+                return false;
+            }
+        }
+        if active || state.force_persist {
+            return false;
+        }
+
+        match self.try_unregister(&mut state) {
+            Ok(()) => true,
+            Err(e) => {
+                info!("Not exiting: {}", e);
+                self.re_register(&mut state).unwrap_or_else(|e| {
+                    error!("Failed to register services again: {}", e);
+                });
+                false
+            }
+        }
+    }
+}
+
+impl LocalBinder for ClientCallback {
+    fn on_transact(&self, code: u32, data: &mut Parcel, _reply: &mut Parcel, _flags: u32) -> Result<()> {
+        if code != ON_CLIENTS {
+            return Err(StatusCode::UnknownTransaction.into());
+        }
+        data.enforce_aidl_interface(INTERFACE_CLIENT_CALLBACK)?;
+        let object = data.get_obj()?;
+        data.get_i32()?; // stability
+        let has_clients = data.get_i32()? != 0;
+        let counter = match self.counter.upgrade() {
+            Some(counter) => counter,
+            None => return Ok(()),
+        };
+        match object {
+            Object::Binder(b) => counter.on_clients(b as usize as BinderPtr, has_clients),
+            Object::Handle(h) => warn!("Client notification for remote object {}", h),
+            Object::Local(o) => counter.on_clients(o.cookie(), has_clients),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use binder::Binder;
+    use binder::mock::MockDriver;
+    use binder::tests::{context_manager, server, Echo};
+    use errors::*;
+    use service::{LocalBinder, Object, Parcel, RemoteBinder, ServiceManager, ServiceManagerFlavor};
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, Weak};
+    use super::{ClientCallback, LazyServiceRegistrar, INTERFACE_CLIENT_CALLBACK, ON_CLIENTS};
+
+    const ADD_SERVICE: u32 = 3;
+    const REGISTER_CLIENT_CALLBACK: u32 = 12;
+    const TRY_UNREGISTER_SERVICE: u32 = 13;
+    const STABILITY: i32 = 0b00_1100;
+
+    /// The client callback related calls of the Android 13 service manager.
+    #[derive(Default)]
+    struct Manager {
+        binder: Mutex<Weak<Binder>>,
+        /// The services and their client callbacks by name.
+        services: Mutex<BTreeMap<String, (RemoteBinder, Option<RemoteBinder>)>>,
+        /// Services `tryUnregisterService` refuses to remove.
+        busy: Mutex<Vec<String>>,
+    }
+
+    impl Manager {
+        fn remote(&self, data: &mut Parcel) -> Result<RemoteBinder> {
+            let binder = self.binder.lock().unwrap().upgrade().unwrap();
+            let remote = match data.get_obj()? {
+                Object::Handle(handle) => RemoteBinder::new(&binder, handle)?,
+                _ => return Err(StatusCode::BadType.into()),
+            };
+            assert_eq!(data.get_i32()?, STABILITY);
+            Ok(remote)
+        }
+
+        fn names(&self) -> Vec<String> {
+            self.services.lock().unwrap().keys().cloned().collect()
+        }
+
+        /// Tell the registrar whether `name` has clients.
+        fn notify(&self, name: &str, has_clients: bool) {
+            let (service, callback) = self.services.lock().unwrap()[name].clone();
+            let mut parcel = Parcel::default();
+            parcel.put_aidl_interface_token(INTERFACE_CLIENT_CALLBACK).unwrap();
+            parcel.put_handle(service.handle()).unwrap();
+            parcel.put_i32(STABILITY).unwrap();
+            parcel.put_bool(has_clients).unwrap();
+            callback.unwrap().transact(ON_CLIENTS, &parcel, 0).unwrap();
+        }
+    }
+
+    impl LocalBinder for Manager {
+        fn on_transact(&self, code: u32, data: &mut Parcel, reply: &mut Parcel, _flags: u32) -> Result<()> {
+            data.enforce_aidl_interface("android.os.IServiceManager")?;
+            let name = data.get_str16()?;
+            let service = self.remote(data)?;
+            match code {
+                ADD_SERVICE => {
+                    self.services.lock().unwrap().insert(name, (service, None));
+                }
+                REGISTER_CLIENT_CALLBACK => {
+                    let callback = self.remote(data)?;
+                    match self.services.lock().unwrap().get_mut(&name) {
+                        Some(entry) if entry.0 == service => entry.1 = Some(callback),
+                        _ => return Err(StatusCode::BadValue.into()),
+                    }
+                }
+                TRY_UNREGISTER_SERVICE if self.busy.lock().unwrap().contains(&name) => {
+                    reply.put_i32(ExceptionCode::IllegalState as i32)?;
+                    reply.put_str16("Service has clients")?;
+                    return reply.put_i32(0); // stack trace header
+                }
+                TRY_UNREGISTER_SERVICE => {
+                    self.services.lock().unwrap().remove(&name);
+                }
+                _ => return Err(StatusCode::UnknownTransaction.into()),
+            }
+            reply.put_i32(0) // status header
+        }
+    }
+
+    /// A registrar of the services `names` counting its exits.
+    fn registrar(names: &[&str]) -> (Arc<Manager>, LazyServiceRegistrar, Arc<AtomicUsize>) {
+        let driver = MockDriver::new();
+        let manager = Arc::new(Manager::default());
+        let binder = context_manager(&driver, manager.clone());
+        *manager.binder.lock().unwrap() = Arc::downgrade(&binder);
+
+        let sm = ServiceManager::with_flavor(server(&driver), ServiceManagerFlavor::Android13);
+        let exits = Arc::new(AtomicUsize::new(0));
+        let counted = exits.clone();
+        let registrar = LazyServiceRegistrar::with_exit(sm, Box::new(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        }));
+        for name in names {
+            registrar.register_service(name, Arc::new(Echo), false, 8).unwrap();
+        }
+        assert_eq!(manager.names(), names);
+        (manager, registrar, exits)
+    }
+
+    #[test]
+    fn exits_once_no_service_has_clients() {
+        let (manager, _registrar, exits) = registrar(&["a", "b"]);
+        manager.notify("a", true);
+        manager.notify("b", true);
+        manager.notify("a", false);
+        assert_eq!(exits.load(Ordering::SeqCst), 0);
+        assert_eq!(manager.names(), ["a", "b"]);
+
+        manager.notify("b", false);
+        assert_eq!(exits.load(Ordering::SeqCst), 1);
+        assert!(manager.names().is_empty());
+    }
+
+    #[test]
+    fn services_are_registered_again_if_one_has_clients() {
+        let (manager, _registrar, exits) = registrar(&["a", "b"]);
+        manager.busy.lock().unwrap().push("b".to_owned());
+        manager.notify("a", true);
+        manager.notify("a", false);
+        // "a" was unregistered before "b" refused.
+        assert_eq!(exits.load(Ordering::SeqCst), 0);
+        assert_eq!(manager.names(), ["a", "b"]);
+
+        manager.busy.lock().unwrap().clear();
+        manager.notify("a", false);
+        assert_eq!(exits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn force_persist_keeps_the_process() {
+        let (manager, registrar, exits) = registrar(&["a"]);
+        registrar.force_persist(true);
+        manager.notify("a", true);
+        manager.notify("a", false);
+        assert_eq!(exits.load(Ordering::SeqCst), 0);
+        assert_eq!(manager.names(), ["a"]);
+
+        registrar.force_persist(false);
+        assert_eq!(exits.load(Ordering::SeqCst), 1);
+        assert!(manager.names().is_empty());
+    }
+
+    #[test]
+    fn active_services_callback_is_called_on_changes() {
+        let (manager, registrar, exits) = registrar(&["a", "b"]);
+        let calls = Arc::new(Mutex::new(vec![]));
+        let recorded = calls.clone();
+        registrar.set_active_services_callback(move |active| {
+            recorded.lock().unwrap().push(active);
+            true
+        });
+        manager.notify("a", true);
+        manager.notify("b", true);
+        manager.notify("a", false);
+        manager.notify("b", false);
+        manager.notify("b", false);
+        assert_eq!(*calls.lock().unwrap(), [true, false]);
+        assert_eq!(exits.load(Ordering::SeqCst), 0);
+        assert_eq!(manager.names(), ["a", "b"]);
+
+        manager.busy.lock().unwrap().push("b".to_owned());
+        assert!(registrar.try_unregister().is_err());
+        assert_eq!(manager.names(), ["b"]);
+        registrar.re_register().unwrap();
+        assert_eq!(manager.names(), ["a", "b"]);
+        manager.busy.lock().unwrap().clear();
+        registrar.try_unregister().unwrap();
+        assert!(manager.names().is_empty());
+        registrar.re_register().unwrap();
+        assert_eq!(manager.names(), ["a", "b"]);
+        // The client callbacks are registered again as well.
+        manager.notify("a", true);
+        assert_eq!(*calls.lock().unwrap(), [true, false, true]);
+    }
+
+    #[test]
+    fn client_notifications_are_parsed() {
+        let (_manager, registrar, exits) = registrar(&[]);
+        let object: Arc<dyn LocalBinder> = Arc::new(Echo);
+        registrar.counter.state.lock().unwrap().services.push(super::Registration {
+            name: "a".to_owned(),
+            object: object.clone(),
+            allow_isolated: false,
+            dump_priority: 8,
+            has_clients: false,
+            registered: false,
+        });
+        let callback = ClientCallback { counter: Arc::downgrade(&registrar.counter) };
+        let notify = |object: Object, interface: &str, code: u32| {
+            let mut data = Parcel::default();
+            data.put_aidl_interface_token(interface).unwrap();
+            data.put(&object).unwrap();
+            data.put_i32(STABILITY).unwrap();
+            data.put_bool(true).unwrap();
+            callback.on_transact(code, &mut data, &mut Parcel::default(), 0)
+        };
+        let has_clients = || registrar.counter.state.lock().unwrap().services[0].has_clients;
+
+        // Remote objects and unknown services are ignored.
+        notify(Object::Handle(1), INTERFACE_CLIENT_CALLBACK, ON_CLIENTS).unwrap();
+        notify(Object::Local(Arc::new(Echo)), INTERFACE_CLIENT_CALLBACK, ON_CLIENTS).unwrap();
+        assert!(!has_clients());
+        let e = notify(Object::Local(object.clone()), "android.os.IOther", ON_CLIENTS).unwrap_err();
+        assert_eq!(e.status(), StatusCode::BadType as i32);
+        let e = notify(Object::Local(object.clone()), INTERFACE_CLIENT_CALLBACK, 2).unwrap_err();
+        assert_eq!(e.status(), StatusCode::UnknownTransaction as i32);
+        assert!(!has_clients());
+
+        // Without a driver the object is read back by its cookie.
+        notify(Object::Local(object), INTERFACE_CLIENT_CALLBACK, ON_CLIENTS).unwrap();
+        assert!(has_clients());
+        assert_eq!(exits.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,38 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use errors::*;
+use super::Parcel;
+use types::BinderPtr;
+
+/// An object hosted in this process that answers incoming transactions.
+///
+/// This is the counterpart of libbinder's `BBinder`. Implementations are
+/// invoked from the looper thread that received the transaction.
+pub trait LocalBinder: Send + Sync {
+    /// Handle transaction `code` with the arguments in `data` and write the
+    /// result into `reply`.
+    ///
+    /// An error of kind `ErrorKind::Status` is sent to the caller as status
+    /// code reply. Any other error is reported as `StatusCode::UnknownError`.
+    /// Nothing is sent back for one way transactions.
+    fn on_transact(&self, code: u32, data: &mut Parcel, reply: &mut Parcel, flags: u32) -> Result<()>;
+}
+
+impl dyn LocalBinder {
+    /// The address of the object used as node pointer and cookie towards the driver.
+    pub(crate) fn cookie(&self) -> BinderPtr {
+        self as *const dyn LocalBinder as *const () as usize as BinderPtr
+    }
+}
@@ -12,10 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod lazy_service_registrar;
+mod local;
 mod parcel;
+mod parcelable;
+mod policy;
+pub(crate) mod remote;
 mod service_manager;
+mod service_manager_server;
+mod service_notification;
 mod service;
 
+pub use self::lazy_service_registrar::LazyServiceRegistrar;
+pub use self::local::LocalBinder;
 pub use self::service::Service;
-pub use self::parcel::Parcel;
-pub use self::service_manager::ServiceManager;
+pub use self::parcel::{Object, Parcel};
+pub use self::parcelable::Parcelable;
+pub use android_binder_derive::Parcelable;
+pub use self::policy::{Access, Policy};
+pub use self::remote::{DeathRecipient, RemoteBinder};
+pub use self::service_manager_server::ServiceManagerServer;
+pub use self::service_notification::ServiceNotification;
+pub use self::service_manager::{ServiceManager, ServiceManagerFlavor, ServiceDebugInfo, ServiceList,
+                                DUMP_FLAG_PRIORITY_CRITICAL, DUMP_FLAG_PRIORITY_HIGH, DUMP_FLAG_PRIORITY_NORMAL,
+                                DUMP_FLAG_PRIORITY_DEFAULT, DUMP_FLAG_PRIORITY_ALL, DUMP_FLAG_PROTO};
@@ -12,36 +12,177 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use errors::*;
-use std::mem::size_of;
+use std::fmt;
+use std::io;
 use std::ops::Deref;
-use types::{BinderType, BinderPtr, FlatBinderObject, FlatBinderFlags};
-use utils::any_as_u8_slice;
+use std::sync::Arc;
+use binder::Buffer;
+use super::{LocalBinder, Parcelable};
+use types::{BinderType, BinderPtr, BinderSize, FlatBinderObject, FlatBinderFlags, Protocol};
 
 const STRICT_MODE_PENALTY_GATHER: i32 = 0x40 << 16;
+const UNSET_WORK_SOURCE: i32 = -1;
+/// 'SYST', the interface token header of the system partition.
+const HEADER_SYSTEM: i32 = 0x5359_5354;
 
+/// `len` rounded up to 4 bytes.
+fn padded(len: usize) -> Option<usize> {
+    len.checked_add(3).map(|l| l & !3)
+}
+
+/// A flat binder object. `get_obj` returns objects of this process as
+/// `Binder`, `Local` holds one to send.
+#[derive(Clone)]
 pub enum Object {
     Handle(u32),
     Binder(*mut ()),
+    Local(Arc<dyn LocalBinder>),
 }
 
-#[derive(Default, Debug)]
+/// Data of a transaction. Writes append to the data, reads advance the
+/// data position and leave the data in place.
+#[derive(Default)]
 pub struct Parcel {
     data: Vec<u8>,
+    /// Received data that is read in place until the parcel is modified.
+    buffer: Option<Buffer>,
+    /// Number of bytes read.
+    position: usize,
+    /// Positions of the flat binder objects in `data`.
+    offsets: Vec<BinderSize>,
+    /// Local objects referenced by the flat binder objects in `data`.
+    objects: Vec<Arc<dyn LocalBinder>>,
+    /// The layout of the flat binder objects in `data`.
+    protocol: Protocol,
+}
+
+impl fmt::Debug for Parcel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Parcel")
+            .field("data", &&**self)
+            .field("offsets", &self.offsets)
+            .finish()
+    }
 }
 
 impl Deref for Parcel {
-    type Target = Vec<u8>;
+    type Target = [u8];
 
     fn deref(&self) -> &Self::Target {
-        &self.data
+        match self.buffer {
+            Some(ref buffer) => buffer.data(),
+            None => &self.data,
+        }
     }
 }
 
 impl Parcel {
     pub fn from_buf(d: &[u8]) -> Parcel {
-        Parcel { data: d.to_vec() }
+        Parcel {
+            data: d.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Create a parcel reading the received `buffer` in place.
+    pub(crate) fn from_buffer(buffer: Buffer) -> Parcel {
+        Parcel {
+            offsets: buffer.offsets(),
+            protocol: buffer.protocol(),
+            buffer: Some(buffer),
+            ..Default::default()
+        }
+    }
+
+    /// The data to write to. Received data is copied and its buffer freed.
+    fn data_mut(&mut self) -> &mut Vec<u8> {
+        if let Some(buffer) = self.buffer.take() {
+            self.data = buffer.data().to_vec();
+        }
+        &mut self.data
+    }
+
+    /// The data not read yet.
+    fn remaining(&self) -> &[u8] {
+        &self[self.position.min(self.len())..]
+    }
+
+    /// Positions of the flat binder objects written to or received with this parcel.
+    pub fn offsets(&self) -> &[BinderSize] {
+        &self.offsets
+    }
+
+    /// The number of bytes in the parcel.
+    pub fn data_size(&self) -> usize {
+        self.len()
+    }
+
+    /// The offset of the next read. Reads don't consume the data, so a
+    /// parcel can be read again after moving the position back.
+    pub fn data_position(&self) -> usize {
+        self.position
+    }
+
+    /// Move the offset of the next read to `position`. Fails with
+    /// `StatusCode::BadIndex` if `position` is past the end of the data.
+    pub fn set_data_position(&mut self, position: usize) -> Result<()> {
+        if position > self.len() {
+            return Err(StatusCode::BadIndex.into());
+        }
+        self.position = position;
+        Ok(())
+    }
+
+    /// Move the offset of the next read `size` bytes past `start`, e.g to
+    /// skip a section whose size was read. Fails with `StatusCode::BadValue`
+    /// for negative sizes and `StatusCode::BadIndex` past the end of the data.
+    pub(crate) fn seek(&mut self, start: usize, size: i32) -> Result<()> {
+        if size < 0 {
+            warn!("Invalid section size {}", size);
+            return Err(StatusCode::BadValue.into());
+        }
+        let position = start.checked_add(size as usize).ok_or_else(|| Error::from(StatusCode::BadIndex))?;
+        self.set_data_position(position)
+    }
+
+    /// The number of bytes left to read.
+    pub fn data_avail(&self) -> usize {
+        self.len().saturating_sub(self.position)
+    }
+
+    pub(crate) fn objects(&self) -> &[Arc<dyn LocalBinder>] {
+        &self.objects
+    }
+
+    pub(crate) fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// A copy of this parcel with the flat binder objects encoded for `protocol`.
+    pub(crate) fn to_protocol(&self, protocol: Protocol) -> Result<Parcel> {
+        let mut parcel = Parcel {
+            objects: self.objects.clone(),
+            protocol,
+            ..Default::default()
+        };
+        let mut position = 0;
+        for offset in &self.offsets {
+            let offset = *offset as usize;
+            let end = offset.checked_add(FlatBinderObject::size(self.protocol));
+            if offset < position || offset & 3 != 0 || end.is_none_or(|end| end > self.len()) {
+                warn!("Invalid object offset {}", offset);
+                return Err(StatusCode::BadValue.into());
+            }
+            parcel.data.extend_from_slice(&self[position..offset]);
+            let o = FlatBinderObject::decode(self.protocol, &mut &self[offset..])?;
+            parcel.offsets.push(parcel.data.len() as BinderSize);
+            o.encode(protocol, &mut parcel.data);
+            position = offset + FlatBinderObject::size(self.protocol);
+        }
+        parcel.data.extend_from_slice(&self[position..]);
+        Ok(parcel)
     }
 
     pub fn put_interface_token(&mut self, interface: &str) -> Result<()> {
@@ -49,123 +190,775 @@ impl Parcel {
         self.put_str16(interface) // interface token
     }
 
+    /// Write the interface token as libbinder of Android 11 and later does for
+    /// the system partition. The work source uid and the partition header
+    /// precede the interface name.
+    pub fn put_aidl_interface_token(&mut self, interface: &str) -> Result<()> {
+        self.put_i32(STRICT_MODE_PENALTY_GATHER)?; // strict mode
+        self.put_i32(UNSET_WORK_SOURCE)?; // work source
+        self.put_i32(HEADER_SYSTEM)?; // partition header
+        self.put_str16(interface) // interface token
+    }
+
     pub fn put_u8(&mut self, n: u8) -> Result<()> {
-        self.data.push(n);
+        self.data_mut().push(n);
         Ok(())
     }
 
     pub fn put_i16(&mut self, n: i16) -> Result<()> {
-        self.data.write_i16::<LittleEndian>(n).chain_err(
+        self.data_mut().write_i16::<LittleEndian>(n).chain_err(
             || "Failed to put i16",
         )
     }
 
     pub fn put_u16(&mut self, n: u16) -> Result<()> {
-        self.data.write_u16::<LittleEndian>(n).chain_err(
+        self.data_mut().write_u16::<LittleEndian>(n).chain_err(
             || "Failed to put u16",
         )
     }
 
     pub fn put_i32(&mut self, n: i32) -> Result<()> {
-        self.data.write_i32::<LittleEndian>(n).chain_err(
+        self.data_mut().write_i32::<LittleEndian>(n).chain_err(
             || "Failed to put i32",
         )
     }
 
     pub fn put_u32(&mut self, n: u32) -> Result<()> {
-        self.data.write_u32::<LittleEndian>(n).chain_err(
+        self.data_mut().write_u32::<LittleEndian>(n).chain_err(
             || "Failed to put u32",
         )
     }
 
+    pub fn put_i64(&mut self, n: i64) -> Result<()> {
+        self.data_mut().write_i64::<LittleEndian>(n).chain_err(
+            || "Failed to put i64",
+        )
+    }
+
+    pub fn put_u64(&mut self, n: u64) -> Result<()> {
+        self.data_mut().write_u64::<LittleEndian>(n).chain_err(
+            || "Failed to put u64",
+        )
+    }
+
+    pub fn put_f32(&mut self, n: f32) -> Result<()> {
+        self.data_mut().write_f32::<LittleEndian>(n).chain_err(
+            || "Failed to put f32",
+        )
+    }
+
+    pub fn put_f64(&mut self, n: f64) -> Result<()> {
+        self.data_mut().write_f64::<LittleEndian>(n).chain_err(
+            || "Failed to put f64",
+        )
+    }
+
+    /// Write `b` as `i32` like libbinder.
+    pub fn put_bool(&mut self, b: bool) -> Result<()> {
+        self.put_i32(if b { 1 } else { 0 })
+    }
+
+    /// Write `c` as UTF-16 code unit in an `i32` like libbinder. Fails with
+    /// `StatusCode::BadValue` for characters outside the basic multilingual plane.
+    pub fn put_char(&mut self, c: char) -> Result<()> {
+        let mut units = [0; 2];
+        match *c.encode_utf16(&mut units) {
+            [unit] => self.put_i32(i32::from(unit)),
+            _ => Err(StatusCode::BadValue.into()),
+        }
+    }
+
+    /// Write the length of an array or string. `StatusCode::BadValue` if it
+    /// exceeds an `i32`.
+    pub(crate) fn put_len(&mut self, len: usize) -> Result<()> {
+        if len > i32::MAX as usize {
+            return Err(StatusCode::BadValue.into());
+        }
+        self.put_i32(len as i32)
+    }
+
+    /// Write the length of `d` followed by `d`, padded to 4 bytes.
+    pub fn put_byte_array(&mut self, d: &[u8]) -> Result<()> {
+        self.put_len(d.len())?;
+        self.put_padded(d);
+        Ok(())
+    }
+
+    /// Like `put_byte_array` but write a length of -1 for `None`.
+    pub fn put_nullable_byte_array(&mut self, d: Option<&[u8]>) -> Result<()> {
+        match d {
+            Some(d) => self.put_byte_array(d),
+            None => self.put_i32(-1),
+        }
+    }
+
+    /// Write the count of `values` followed by `values` of `size` bytes each,
+    /// encoded in bulk by `write`. The fast path of `Parcelable::write_array`
+    /// for numbers.
+    pub(crate) fn put_array<T>(&mut self, values: &[T], size: usize, write: fn(&[T], &mut [u8])) -> Result<()> {
+        self.put_len(values.len())?;
+        let data = self.data_mut();
+        let start = data.len();
+        data.resize(start + values.len() * size, 0);
+        write(values, &mut data[start..]);
+        Ok(())
+    }
+
+    /// Write the count of `values` followed by the values, see `Parcelable`.
+    pub fn put_vec<T: Parcelable>(&mut self, values: &[T]) -> Result<()> {
+        T::write_array(values, self)
+    }
+
+    /// Like `put_vec` but write a count of -1 for `None`, e.g for a `@nullable` array.
+    pub fn put_nullable_vec<T: Parcelable>(&mut self, values: Option<&[T]>) -> Result<()> {
+        match values {
+            Some(values) => T::write_array(values, self),
+            None => self.put_i32(-1),
+        }
+    }
+
+    /// Write `s` as UTF-16 string, e.g a `String` or `@utf8InCpp String` of AIDL.
     pub fn put_str16(&mut self, s: &str) -> Result<()> {
-        self.data.reserve(size_of::<i32>() + s.len() * 2 + 2);
-        self.put_i32((s.len()) as i32)?;
-        for c in s.encode_utf16() {
-            self.put_u16(c)?;
+        let mut d = Vec::with_capacity(s.len() * 2 + 2);
+        let mut l = 0;
+        for c in s.encode_utf16().chain(Some(0)) { // zero termination
+            d.write_u16::<LittleEndian>(c).chain_err(|| "Failed to put str16")?;
+            l += 1;
+        }
+        self.put_i32(l - 1)?;
+        self.put_padded(&d);
+        Ok(())
+    }
+
+    /// Like `put_str16` but write a length of -1 for `None`.
+    pub fn put_nullable_str16(&mut self, s: Option<&str>) -> Result<()> {
+        match s {
+            Some(s) => self.put_str16(s),
+            None => self.put_i32(-1),
         }
-        self.put_u16(0)?; // zero termination
-        // padding
-        if (self.data.len() % 4) != 0 {
-            let l = self.data.len();
-            self.data.resize(l + 4 - (l % 4), 0);
+    }
+
+    /// Write `s` as UTF-8 string like libbinder's `writeString8`.
+    pub fn put_str8(&mut self, s: &str) -> Result<()> {
+        self.put_len(s.len())?;
+        let data = self.data_mut();
+        data.extend_from_slice(s.as_bytes());
+        data.push(0); // zero termination
+        self.pad();
+        Ok(())
+    }
+
+    /// Like `put_str8` but write a length of -1 for `None`.
+    pub fn put_nullable_str8(&mut self, s: Option<&str>) -> Result<()> {
+        match s {
+            Some(s) => self.put_str8(s),
+            None => self.put_i32(-1),
         }
+    }
+
+    /// Append `d` padded to 4 bytes.
+    fn put_padded(&mut self, d: &[u8]) {
+        self.data_mut().extend_from_slice(d);
+        self.pad();
+    }
+
+    /// Pad the data to 4 bytes.
+    fn pad(&mut self) {
+        let data = self.data_mut();
+        let l = data.len();
+        data.resize((l + 3) & !3, 0);
+    }
+
+    /// Write a reference to the local `object`. The object is registered
+    /// with the `Binder` this parcel is sent with.
+    pub fn put_binder(&mut self, object: Arc<dyn LocalBinder>) -> Result<()> {
+        let cookie = object.cookie();
+        self.put_flat_object(FlatBinderObject {
+            type_: BinderType::Binder as u32,
+            flags: 0x7F | FlatBinderFlags::AcceptFds as u32,
+            handle_binder: cookie,
+            cookie,
+        })?;
+        self.objects.push(object);
         Ok(())
     }
 
-    // TODO: Understand how to transmit a binder.
-    pub fn put_binder(&mut self, binder: BinderPtr, cookie: BinderPtr) -> Result<()> {
-        let o = FlatBinderObject {
+    /// Write a null binder. Like libbinder no offset is recorded since the
+    /// driver has nothing to translate.
+    pub fn put_null_binder(&mut self) -> Result<()> {
+        let protocol = self.protocol;
+        self.pad();
+        FlatBinderObject {
             type_: BinderType::Binder as u32,
             flags: 0x7F | FlatBinderFlags::AcceptFds as u32,
-            handle_binder: binder as BinderPtr,
-            cookie: cookie as BinderPtr,
-        };
-        self.data.extend(any_as_u8_slice(&o));
-        if (self.data.len() % 4) != 0 {
-            let l = self.data.len();
-            self.data.resize(l + 4 - (l % 4), 0);
+            handle_binder: 0,
+            cookie: 0,
+        }.encode(protocol, self.data_mut());
+        Ok(())
+    }
+
+    /// Write a reference to the remote object `handle`.
+    pub fn put_handle(&mut self, handle: u32) -> Result<()> {
+        self.put_flat_object(FlatBinderObject {
+            type_: BinderType::Handle as u32,
+            flags: 0x7F | FlatBinderFlags::AcceptFds as u32,
+            handle_binder: handle as BinderPtr,
+            cookie: 0,
+        })
+    }
+
+    fn put_flat_object(&mut self, o: FlatBinderObject) -> Result<()> {
+        // The driver requires objects to be 4 byte aligned
+        let protocol = self.protocol;
+        self.pad();
+        let data = self.data_mut();
+        let offset = data.len() as BinderSize;
+        o.encode(protocol, data);
+        self.offsets.push(offset);
+        Ok(())
+    }
+
+    /// Read an interface token written by `put_aidl_interface_token` and fail
+    /// with `StatusCode::BadType` if it isn't the one of `interface`.
+    pub fn enforce_aidl_interface(&mut self, interface: &str) -> Result<()> {
+        self.get_i32()?; // strict mode
+        self.get_i32()?; // work source
+        let header = self.get_i32()?;
+        if header != HEADER_SYSTEM {
+            warn!("Invalid interface token header 0x{:x}", header);
+            return Err(StatusCode::BadType.into());
+        }
+        let token = self.get_str16()?;
+        if token != interface {
+            warn!("Invalid interface {}, expected {}", token, interface);
+            return Err(StatusCode::BadType.into());
         }
         Ok(())
     }
 
-    // pub fn put_object(&mut self, type_: BinderType, object: Object, _cookie: ()) -> Result<()> {
-    //     match object {
-    //         Object::Handle(h) => {
-    //             let o = FlatBinderObject {
-    //                 type_: type_ as u32,
-    //                 flags: 0x7F | FlatBinderFlags::AcceptFds as u32,
-    //                 handle: h,
-    //                 cookie: self.as_ptr() as BinderPtr,
-    //             };
-    //             self.data.extend(any_as_u8_slice(&o));
-    //             if (self.data.len() % 4) != 0 {
-    //                 let l = self.data.len();
-    //                 self.data.resize(l + 4 - (l % 4), 0);
-    //             }
-    //         },
-    //         Object::Binder(p) => {
-    //             let o = FlatBinderObject {
-    //                 type_: type_ as u32,
-    //                 flags: 0x7F | FlatBinderFlags::AcceptFds as u32,
-    //                 handle: p as BinderPtr,
-    //                 cookie: self.as_ptr() as BinderPtr,
-    //             };
-    //         },
-    //         _ => unimplemented!(),
-    //     }
-    //     Ok(())
-    // }
+    /// Read a value with `read` and advance past it.
+    fn read<T, F>(&mut self, read: F) -> Result<T>
+    where
+        F: FnOnce(&mut &[u8]) -> io::Result<T>,
+    {
+        let mut d = self.remaining();
+        let l = d.len();
+        let r = read(&mut d).map_err(|_| Error::from(StatusCode::NotEnoughData))?;
+        self.position += l - d.len();
+        Ok(r)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8> {
+        self.read(|d| d.read_u8())
+    }
+
+    pub fn get_i16(&mut self) -> Result<i16> {
+        self.read(|d| d.read_i16::<LittleEndian>())
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16> {
+        self.read(|d| d.read_u16::<LittleEndian>())
+    }
 
     pub fn get_i32(&mut self) -> Result<i32> {
-        let r = self.data.as_slice().read_i32::<LittleEndian>().chain_err(
-            || "Data exhausted",
-        )?;
-        self.data.drain(..size_of::<i32>());
-        Ok(r)
+        self.read(|d| d.read_i32::<LittleEndian>())
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32> {
+        self.read(|d| d.read_u32::<LittleEndian>())
+    }
+
+    pub fn get_i64(&mut self) -> Result<i64> {
+        self.read(|d| d.read_i64::<LittleEndian>())
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64> {
+        self.read(|d| d.read_u64::<LittleEndian>())
+    }
+
+    pub fn get_f32(&mut self) -> Result<f32> {
+        self.read(|d| d.read_f32::<LittleEndian>())
+    }
+
+    pub fn get_f64(&mut self) -> Result<f64> {
+        self.read(|d| d.read_f64::<LittleEndian>())
+    }
+
+    /// Read a `bool` written as `i32`. Anything but 0 is `true`.
+    pub fn get_bool(&mut self) -> Result<bool> {
+        Ok(self.get_i32()? != 0)
+    }
+
+    /// Read a UTF-16 code unit written as `i32`.
+    pub fn get_char(&mut self) -> Result<char> {
+        let c = self.get_i32()?;
+        ::std::char::from_u32(c as u32 & 0xffff).ok_or_else(|| StatusCode::BadValue.into())
+    }
+
+    /// Read the length of a string or array. `None` for a length of -1.
+    fn get_length(&mut self) -> Result<Option<usize>> {
+        match self.get_i32()? {
+            -1 => Ok(None),
+            l if l < 0 => {
+                warn!("Invalid length {}", l);
+                Err(StatusCode::BadValue.into())
+            }
+            l => Ok(Some(l as usize)),
+        }
+    }
+
+    /// Read the element count of an array. `None` for a count of -1. Fails
+    /// with `StatusCode::NotEnoughData` if the rest of the parcel can't hold
+    /// as many elements of at least `element_size` bytes.
+    pub(crate) fn get_array_length(&mut self, element_size: usize) -> Result<Option<usize>> {
+        let n = match self.get_length()? {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+        match n.checked_mul(element_size) {
+            Some(size) if size <= self.data_avail() => Ok(Some(n)),
+            _ => {
+                warn!("{} elements exceed the {} bytes left", n, self.data_avail());
+                Err(StatusCode::NotEnoughData.into())
+            }
+        }
+    }
+
+    /// Read values of `size` bytes each written by `put_array`, decoded in
+    /// bulk by `read`. `None` for a count of -1.
+    pub(crate) fn get_array<T>(&mut self, size: usize, read: fn(&[u8], &mut [T])) -> Result<Option<Vec<T>>>
+    where
+        T: Clone + Default,
+    {
+        let n = match self.get_array_length(size)? {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+        let mut values = vec![T::default(); n];
+        let position = self.position;
+        read(&self[position..position + n * size], &mut values);
+        self.position += n * size;
+        Ok(Some(values))
+    }
+
+    /// Read `len` bytes followed by padding to 4 bytes. The padding isn't
+    /// required to be zero since the legacy service manager doesn't clear it.
+    fn get_padded(&mut self, len: usize) -> Result<&[u8]> {
+        let position = self.position;
+        match padded(len) {
+            Some(size) if size <= self.data_avail() => {
+                self.position += size;
+                Ok(&self[position..position + len])
+            }
+            _ => Err(StatusCode::NotEnoughData.into()),
+        }
+    }
+
+    /// Read a zero terminated string of `len` units of `unit` bytes.
+    fn get_terminated(&mut self, len: usize, unit: usize) -> Result<&[u8]> {
+        let size = len.checked_add(1).and_then(|l| l.checked_mul(unit)).ok_or_else(|| {
+            Error::from(StatusCode::NotEnoughData)
+        })?;
+        let d = self.get_padded(size)?;
+        let (s, terminator) = d.split_at(len * unit);
+        if terminator.iter().any(|b| *b != 0) {
+            warn!("String of length {} isn't zero terminated", len);
+            return Err(StatusCode::BadValue.into());
+        }
+        Ok(s)
+    }
+
+    pub fn get_byte_array(&mut self) -> Result<Vec<u8>> {
+        self.get_nullable_byte_array()?.ok_or_else(|| StatusCode::UnexpectedNull.into())
+    }
+
+    pub fn get_nullable_byte_array(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.get_length()? {
+            Some(l) => Ok(Some(self.get_padded(l)?.to_vec())),
+            None => Ok(None),
+        }
     }
 
     pub fn get_str16(&mut self) -> Result<String> {
-        let l = self.get_i32()? as usize;
-        debug!("length: {}", l);
-        let d = self.data.drain(..((l * 2) + 2)).collect::<Vec<u8>>();
-        let r: &[u16] = {
-            unsafe { ::std::slice::from_raw_parts(d.as_slice().as_ptr() as *const u16, l * 2) }
+        self.get_nullable_str16()?.ok_or_else(|| StatusCode::UnexpectedNull.into())
+    }
+
+    pub fn get_nullable_str16(&mut self) -> Result<Option<String>> {
+        let l = match self.get_length()? {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        let s: Vec<u16> = self.get_terminated(l, 2)?.chunks(2).map(LittleEndian::read_u16).collect();
+        String::from_utf16(&s).map(Some).map_err(|_| {
+            warn!("Invalid UTF-16 string");
+            StatusCode::BadValue.into()
+        })
+    }
+
+    pub fn get_str8(&mut self) -> Result<String> {
+        self.get_nullable_str8()?.ok_or_else(|| StatusCode::UnexpectedNull.into())
+    }
+
+    pub fn get_nullable_str8(&mut self) -> Result<Option<String>> {
+        let l = match self.get_length()? {
+            Some(l) => l,
+            None => return Ok(None),
         };
-        Ok(String::from_utf16(r).chain_err(|| "Invlid string")?)
+        let s = self.get_terminated(l, 1)?.to_vec();
+        String::from_utf8(s).map(Some).map_err(|_| {
+            warn!("Invalid UTF-8 string");
+            StatusCode::BadValue.into()
+        })
+    }
+
+    /// Write `value`, see `Parcelable`.
+    pub fn put<T: Parcelable>(&mut self, value: &T) -> Result<()> {
+        value.write_to_parcel(self)
+    }
+
+    /// Read a `T`, see `Parcelable`.
+    pub fn get<T: Parcelable>(&mut self) -> Result<T> {
+        T::read_from_parcel(self)
+    }
+
+    /// Read values written by `put_vec`. Fails with `StatusCode::UnexpectedNull`
+    /// for a null array and `StatusCode::NotEnoughData` if the count exceeds
+    /// what the rest of the parcel can hold.
+    pub fn get_vec<T: Parcelable>(&mut self) -> Result<Vec<T>> {
+        T::read_array(self)?.ok_or_else(|| StatusCode::UnexpectedNull.into())
+    }
+
+    /// Read values or null written by `put_nullable_vec`.
+    pub fn get_nullable_vec<T: Parcelable>(&mut self) -> Result<Option<Vec<T>>> {
+        T::read_array(self)
+    }
+
+    /// Write the data written by `write` preceded by its size, like the
+    /// fields of a stable AIDL parcelable.
+    pub fn put_sized<F>(&mut self, write: F) -> Result<()>
+    where
+        F: FnOnce(&mut Parcel) -> Result<()>,
+    {
+        let start = self.data_size();
+        self.put_i32(0)?; // size, including its own
+        write(self)?;
+        let size = (self.data_size() - start) as i32;
+        LittleEndian::write_i32(&mut self.data_mut()[start..start + 4], size);
+        Ok(())
+    }
+
+    /// Read data written with `put_sized`. `read` is passed the position
+    /// the data ends at, see `get_sized_field`. Data `read` doesn't know
+    /// about, e.g fields added by a newer writer, is skipped.
+    pub fn get_sized<T, F>(&mut self, read: F) -> Result<T>
+    where
+        F: FnOnce(&mut Parcel, usize) -> Result<T>,
+    {
+        let start = self.position;
+        let size = self.get_i32()?;
+        if size < 4 {
+            warn!("Invalid size {}", size);
+            return Err(StatusCode::BadValue.into());
+        }
+        let end = start.checked_add(size as usize).ok_or_else(|| Error::from(StatusCode::BadIndex))?;
+        if end > self.len() {
+            return Err(StatusCode::NotEnoughData.into());
+        }
+        let value = read(self, end)?;
+        if self.position > end {
+            warn!("Read past the size {}", size);
+            return Err(StatusCode::BadValue.into());
+        }
+        self.position = end;
+        Ok(value)
     }
 
+    /// Read a field of sized data ending at `end`. `None` if the data ends
+    /// before the field, e.g because an older writer didn't know about it.
+    pub fn get_sized_field<T: Parcelable>(&mut self, end: usize) -> Result<Option<T>> {
+        if self.position >= end {
+            return Ok(None);
+        }
+        self.get().map(Some)
+    }
+
+    /// Read the status header AIDL services put in front of a reply.
+    /// An exception is returned as `ErrorKind::Exception` or `ErrorKind::ServiceSpecific`.
+    pub fn get_status(&mut self) -> Result<()> {
+        let exception = self.get_i32()?;
+        if exception == ExceptionCode::HasReplyHeader as i32 {
+            // The header size includes its own size field. Like libbinder
+            // the header is skipped and the reply has no exception.
+            let start = self.position;
+            let size = self.get_i32()?;
+            if size < 4 {
+                warn!("Invalid reply header size {}", size);
+                return Err(StatusCode::BadValue.into());
+            }
+            return self.seek(start, size);
+        }
+        if exception == ExceptionCode::None as i32 {
+            return Ok(());
+        }
+
+        let message = self.get_str16()?;
+        // The stack trace header size includes its own size field, 0 if empty
+        let start = self.position;
+        let stack_trace_size = self.get_i32()?;
+        if stack_trace_size != 0 {
+            if stack_trace_size < 4 {
+                warn!("Invalid stack trace header size {}", stack_trace_size);
+                return Err(StatusCode::BadValue.into());
+            }
+            self.seek(start, stack_trace_size)?;
+        }
+        if exception == ExceptionCode::ServiceSpecific as i32 {
+            let code = self.get_i32()?;
+            return Err(ErrorKind::ServiceSpecific(code, message).into());
+        }
+        Err(ErrorKind::Exception(exception, message).into())
+    }
 
+    /// Read a flat binder object. Fails with `StatusCode::BadType` if no
+    /// object was written at the data position. Null binders are accepted
+    /// without one.
     pub fn get_obj(&mut self) -> Result<Object> {
-        let d = self.data.drain(..size_of::<FlatBinderObject>()).collect::<Vec<u8>>();
-        let o: FlatBinderObject = unsafe { ::std::ptr::read((&d).as_ptr() as *const _) };
-        let t: BinderType = o.type_.into();
-        match t {
-            BinderType::Handle => {
-                let h = o.handle_binder as u32;
-                return Ok(Object::Handle(h));
-            },
-            _ => unimplemented!(),
+        if FlatBinderObject::size(self.protocol) > self.data_avail() {
+            return Err(StatusCode::NotEnoughData.into());
+        }
+        let o = FlatBinderObject::decode(self.protocol, &mut self.remaining())?;
+        // libbinder writes null binders without an offset since the driver
+        // has nothing to translate.
+        let null = o.type_ == BinderType::Binder as u32 && o.handle_binder == 0 && o.cookie == 0;
+        let offset = self.position & 3 == 0 && self.offsets.contains(&(self.position as BinderSize));
+        if !null && !offset {
+            warn!("No object at position {}", self.position);
+            return Err(StatusCode::BadType.into());
+        }
+        self.position += FlatBinderObject::size(self.protocol);
+        match o.type_ {
+            t if t == BinderType::Handle as u32 || t == BinderType::WeakHandle as u32 => {
+                Ok(Object::Handle(o.handle_binder as u32))
+            }
+            t if t == BinderType::Binder as u32 || t == BinderType::WeakBinder as u32 => {
+                Ok(Object::Binder(o.cookie as usize as *mut ()))
+            }
+            t => {
+                warn!("Unsupported object type 0x{:x}", t);
+                Err(StatusCode::BadType.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use errors::*;
+    use super::{Object, Parcel};
+    use types::{BinderType, Protocol};
+
+    /// The status of the error `r` fails with.
+    fn status<T>(r: Result<T>) -> i32 {
+        r.err().expect("Expected an error").status()
+    }
+
+    #[test]
+    fn truncated_primitives_fail() {
+        let mut parcel = Parcel::from_buf(&[1, 2, 3]);
+        assert_eq!(status(parcel.get_i32()), StatusCode::NotEnoughData as i32);
+        assert_eq!(status(parcel.get_u64()), StatusCode::NotEnoughData as i32);
+        assert_eq!(status(parcel.get_f64()), StatusCode::NotEnoughData as i32);
+        assert_eq!(status(parcel.get_bool()), StatusCode::NotEnoughData as i32);
+        // Failed reads don't move the position.
+        assert_eq!(parcel.data_position(), 0);
+        assert_eq!(parcel.get_u16().unwrap(), 0x0201);
+        assert_eq!(status(parcel.get_u16()), StatusCode::NotEnoughData as i32);
+        assert_eq!(parcel.get_u8().unwrap(), 3);
+        assert_eq!(status(parcel.get_u8()), StatusCode::NotEnoughData as i32);
+    }
+
+    #[test]
+    fn invalid_array_counts_fail() {
+        let parcel = |count: i32| {
+            let mut parcel = Parcel::default();
+            parcel.put_i32(count).unwrap();
+            parcel.put_i32(1).unwrap();
+            parcel.put_i32(2).unwrap();
+            parcel
+        };
+        for &count in &[-2, i32::MIN] {
+            assert_eq!(status(parcel(count).get_vec::<i32>()), StatusCode::BadValue as i32);
+            assert_eq!(status(parcel(count).get_vec::<String>()), StatusCode::BadValue as i32);
+            assert_eq!(status(parcel(count).get_byte_array()), StatusCode::BadValue as i32);
+            assert_eq!(status(parcel(count).get_str16()), StatusCode::BadValue as i32);
+        }
+        for &count in &[0x4000_0000, i32::MAX] {
+            assert_eq!(status(parcel(count).get_vec::<i64>()), StatusCode::NotEnoughData as i32);
+            assert_eq!(status(parcel(count).get_vec::<String>()), StatusCode::NotEnoughData as i32);
+            assert_eq!(status(parcel(count).get_byte_array()), StatusCode::NotEnoughData as i32);
+        }
+        // One more element than the data holds.
+        assert_eq!(status(parcel(3).get_vec::<i32>()), StatusCode::NotEnoughData as i32);
+        assert_eq!(status(parcel(9).get_byte_array()), StatusCode::NotEnoughData as i32);
+        assert_eq!(parcel(2).get_vec::<i32>().unwrap(), [1, 2]);
+        assert_eq!(status(parcel(-1).get_vec::<i32>()), StatusCode::UnexpectedNull as i32);
+        assert!(parcel(-1).get_nullable_vec::<i32>().unwrap().is_none());
+    }
+
+    #[test]
+    fn lengths_exceeding_an_i32_fail() {
+        let mut parcel = Parcel::default();
+        parcel.put_len(i32::MAX as usize).unwrap();
+        assert_eq!(status(parcel.put_len(i32::MAX as usize + 1)), StatusCode::BadValue as i32);
+        let units = vec![(); i32::MAX as usize + 1];
+        assert_eq!(status(parcel.put_array(&units, 0, |_, _| ())), StatusCode::BadValue as i32);
+        assert_eq!(parcel.len(), 4);
+    }
+
+    #[test]
+    fn invalid_str16_fail() {
+        // Two units and the terminator but only five bytes.
+        let mut parcel = Parcel::from_buf(&[2, 0, 0, 0, b'a', 0, b'b', 0, 0]);
+        assert_eq!(status(parcel.get_str16()), StatusCode::NotEnoughData as i32);
+        // The unit after the string isn't zero.
+        let mut parcel = Parcel::from_buf(&[1, 0, 0, 0, b'a', 0, b'b', 0]);
+        assert_eq!(status(parcel.get_str16()), StatusCode::BadValue as i32);
+        // An unpaired surrogate.
+        let mut parcel = Parcel::from_buf(&[1, 0, 0, 0, 0x00, 0xd8, 0, 0]);
+        assert_eq!(status(parcel.get_str16()), StatusCode::BadValue as i32);
+
+        let mut parcel = Parcel::from_buf(&[1, 0, 0, 0, b'a', 0, 0, 0]);
+        assert_eq!(parcel.get_str16().unwrap(), "a");
+    }
+
+    #[test]
+    fn objects_past_the_data_fail() {
+        let mut parcel = Parcel::default();
+        parcel.put_handle(1).unwrap();
+        let truncated = parcel.len() - 4;
+        parcel.data.truncate(truncated);
+        assert_eq!(status(parcel.get_obj()), StatusCode::NotEnoughData as i32);
+        assert_eq!(status(parcel.to_protocol(Protocol::V7)), StatusCode::BadValue as i32);
+
+        let mut parcel = Parcel::from_buf(&[0; 32]);
+        parcel.offsets.push(64);
+        assert_eq!(status(parcel.to_protocol(Protocol::V7)), StatusCode::BadValue as i32);
+        parcel.offsets[0] = u64::MAX;
+        assert_eq!(status(parcel.to_protocol(Protocol::V7)), StatusCode::BadValue as i32);
+    }
+
+    #[test]
+    fn misaligned_objects_fail() {
+        let mut object = Parcel::default();
+        object.put_handle(1).unwrap();
+        let mut data = vec![0];
+        data.extend_from_slice(&object);
+        let mut parcel = Parcel::from_buf(&data);
+        parcel.offsets.push(1);
+        assert_eq!(status(parcel.to_protocol(Protocol::V7)), StatusCode::BadValue as i32);
+        parcel.get_u8().unwrap();
+        assert_eq!(status(parcel.get_obj()), StatusCode::BadType as i32);
+    }
+
+    #[test]
+    fn status_none() {
+        let mut parcel = Parcel::default();
+        parcel.put_i32(ExceptionCode::None as i32).unwrap();
+        parcel.put_i32(7).unwrap();
+        parcel.get_status().unwrap();
+        assert_eq!(parcel.get_i32().unwrap(), 7);
+    }
+
+    #[test]
+    fn status_reply_header_is_skipped() {
+        let mut parcel = Parcel::default();
+        parcel.put_i32(ExceptionCode::HasReplyHeader as i32).unwrap();
+        parcel.put_i32(12).unwrap(); // header size
+        parcel.put_i32(-5).unwrap();
+        parcel.put_i32(-6).unwrap();
+        parcel.put_i32(3).unwrap(); // payload
+        parcel.get_status().unwrap();
+        assert_eq!(parcel.get_i32().unwrap(), 3);
+
+        for &size in &[3, -1, 100] {
+            let mut parcel = Parcel::default();
+            parcel.put_i32(ExceptionCode::HasReplyHeader as i32).unwrap();
+            parcel.put_i32(size).unwrap();
+            assert!(parcel.get_status().is_err());
+        }
+    }
+
+    /// A reply with `exception`, `message` and a stack trace header of `stack_trace` bytes.
+    fn exception(exception: ExceptionCode, message: &str, stack_trace: i32) -> Parcel {
+        let mut parcel = Parcel::default();
+        parcel.put_i32(exception as i32).unwrap();
+        parcel.put_str16(message).unwrap();
+        parcel.put_i32(stack_trace).unwrap();
+        for _ in 1..stack_trace / 4 {
+            parcel.put_i32(-1).unwrap();
+        }
+        parcel
+    }
+
+    #[test]
+    fn status_service_specific() {
+        for &stack_trace in &[0, 4, 16] {
+            let mut parcel = exception(ExceptionCode::ServiceSpecific, "failed", stack_trace);
+            parcel.put_i32(42).unwrap();
+            parcel.put_i32(9).unwrap();
+            match *parcel.get_status().unwrap_err().kind() {
+                ErrorKind::ServiceSpecific(42, ref message) => assert_eq!(message, "failed"),
+                ref e => panic!("Unexpected {:?} with a stack trace of {}", e, stack_trace),
+            }
+            assert_eq!(parcel.get_i32().unwrap(), 9);
+        }
+        for &stack_trace in &[1, 3, -4] {
+            let mut parcel = exception(ExceptionCode::ServiceSpecific, "failed", stack_trace);
+            parcel.put_i32(42).unwrap();
+            assert_eq!(status(parcel.get_status()), StatusCode::BadValue as i32);
+        }
+    }
+
+    #[test]
+    fn status_exception() {
+        let mut parcel = exception(ExceptionCode::IllegalArgument, "bad argument", 0);
+        match *parcel.get_status().unwrap_err().kind() {
+            ErrorKind::Exception(code, ref message) => {
+                assert_eq!(code, ExceptionCode::IllegalArgument as i32);
+                assert_eq!(message, "bad argument");
+            }
+            ref e => panic!("Unexpected {:?}", e),
+        }
+        assert_eq!(parcel.data_avail(), 0);
+    }
+
+    #[test]
+    fn null_binders_need_no_offset() {
+        let mut parcel = Parcel::default();
+        parcel.put_null_binder().unwrap();
+        parcel.put_i32(7).unwrap();
+        assert!(parcel.offsets().is_empty());
+        match parcel.get_obj().unwrap() {
+            Object::Binder(b) => assert!(b.is_null()),
+            _ => panic!("Expected a null binder"),
+        }
+        assert_eq!(parcel.get_i32().unwrap(), 7);
+    }
+
+    #[test]
+    fn objects_need_an_offset() {
+        let mut parcel = Parcel::default();
+        parcel.put_u32(BinderType::Handle as u32).unwrap();
+        parcel.put_u32(0).unwrap();
+        parcel.put_u64(0).unwrap();
+        parcel.put_u64(0).unwrap();
+        parcel.put_u32(BinderType::Binder as u32).unwrap();
+        parcel.put_u32(0).unwrap();
+        parcel.put_u64(0x1000).unwrap();
+        parcel.put_u64(0x1000).unwrap();
+        for _ in 0..2 {
+            assert_eq!(status(parcel.get_obj()), StatusCode::BadType as i32);
+            parcel.set_data_position(parcel.data_position() + 24).unwrap();
         }
     }
 }
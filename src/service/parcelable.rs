@@ -0,0 +1,418 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use byteorder::{ByteOrder, LittleEndian};
+use errors::*;
+use std::convert::TryInto;
+use super::{Object, Parcel};
+
+/// A type that can be written to and read from a `Parcel` in the layout of
+/// the corresponding AIDL type.
+///
+/// Structs and enums implement it with `#[derive(Parcelable)]`. Structs are
+/// written like stable AIDL parcelables: a non-null marker, the size of the
+/// fields and the fields. Fields an older writer may not know about are
+/// marked `#[parcelable(default)]` and have to come last, they are the
+/// `Default` of their type if missing. Missing unmarked fields fail with
+/// `StatusCode::NotEnoughData`. Enums without fields are written as their
+/// discriminant, `i32` or `i64` for `#[repr(i64)]`. Enums whose variants
+/// hold one value each are written like AIDL unions: a non-null marker, the
+/// index of the variant and the value. Type parameters are required to be
+/// `Parcelable`.
+pub trait Parcelable: Sized {
+    /// The fewest bytes a value takes in a parcel. Array counts are checked
+    /// against the data left with it before reading any element.
+    const MIN_SIZE: usize = 4;
+
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()>;
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<Self>;
+
+    /// Write `value` or null, for `Option<Self>`. By default a marker of 0 for
+    /// null or 1 precedes the value.
+    fn write_nullable(value: Option<&Self>, parcel: &mut Parcel) -> Result<()> {
+        match value {
+            Some(value) => {
+                parcel.put_i32(1)?;
+                value.write_to_parcel(parcel)
+            }
+            None => parcel.put_i32(0),
+        }
+    }
+
+    /// Read a value or null written by `write_nullable`.
+    fn read_nullable(parcel: &mut Parcel) -> Result<Option<Self>> {
+        match parcel.get_i32()? {
+            0 => Ok(None),
+            _ => Self::read_from_parcel(parcel).map(Some),
+        }
+    }
+
+    /// Write `values`, for `Vec<Self>` and arrays. By default the count
+    /// precedes the values.
+    fn write_array(values: &[Self], parcel: &mut Parcel) -> Result<()> {
+        parcel.put_len(values.len())?;
+        for value in values {
+            value.write_to_parcel(parcel)?;
+        }
+        Ok(())
+    }
+
+    /// Read values or null written by `write_array`, a count of -1 is null.
+    fn read_array(parcel: &mut Parcel) -> Result<Option<Vec<Self>>> {
+        let n = match parcel.get_array_length(Self::MIN_SIZE)? {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+        let mut values = Vec::with_capacity(n);
+        for _ in 0..n {
+            values.push(Self::read_from_parcel(parcel)?);
+        }
+        Ok(Some(values))
+    }
+}
+
+impl Parcelable for bool {
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+        parcel.put_bool(*self)
+    }
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<bool> {
+        parcel.get_bool()
+    }
+}
+
+/// AIDL `byte`, written as `i32`. Arrays are written packed.
+impl Parcelable for i8 {
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+        parcel.put_i32(i32::from(*self))
+    }
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<i8> {
+        Ok(parcel.get_i32()? as i8)
+    }
+
+    fn write_array(values: &[i8], parcel: &mut Parcel) -> Result<()> {
+        let d: Vec<u8> = values.iter().map(|b| *b as u8).collect();
+        parcel.put_byte_array(&d)
+    }
+
+    fn read_array(parcel: &mut Parcel) -> Result<Option<Vec<i8>>> {
+        Ok(parcel.get_nullable_byte_array()?.map(|d| d.into_iter().map(|b| b as i8).collect()))
+    }
+}
+
+/// Like `i8`.
+impl Parcelable for u8 {
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+        parcel.put_i32(i32::from(*self))
+    }
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<u8> {
+        Ok(parcel.get_i32()? as u8)
+    }
+
+    fn write_array(values: &[u8], parcel: &mut Parcel) -> Result<()> {
+        parcel.put_byte_array(values)
+    }
+
+    fn read_array(parcel: &mut Parcel) -> Result<Option<Vec<u8>>> {
+        parcel.get_nullable_byte_array()
+    }
+}
+
+/// AIDL `char`, written as `i32`.
+impl Parcelable for char {
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+        parcel.put_char(*self)
+    }
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<char> {
+        parcel.get_char()
+    }
+}
+
+macro_rules! number_parcelable {
+    ($t:ident, $size:expr, $put:ident, $get:ident, $write_into:ident, $read_into:ident) => {
+        /// Arrays are written and read in bulk.
+        impl Parcelable for $t {
+            const MIN_SIZE: usize = $size;
+
+            fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+                parcel.$put(*self)
+            }
+
+            fn read_from_parcel(parcel: &mut Parcel) -> Result<$t> {
+                parcel.$get()
+            }
+
+            fn write_array(values: &[$t], parcel: &mut Parcel) -> Result<()> {
+                parcel.put_array(values, $size, LittleEndian::$write_into)
+            }
+
+            fn read_array(parcel: &mut Parcel) -> Result<Option<Vec<$t>>> {
+                parcel.get_array($size, LittleEndian::$read_into)
+            }
+        }
+    };
+}
+
+number_parcelable!(i32, 4, put_i32, get_i32, write_i32_into, read_i32_into);
+number_parcelable!(u32, 4, put_u32, get_u32, write_u32_into, read_u32_into);
+number_parcelable!(i64, 8, put_i64, get_i64, write_i64_into, read_i64_into);
+number_parcelable!(u64, 8, put_u64, get_u64, write_u64_into, read_u64_into);
+
+impl Parcelable for f32 {
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+        parcel.put_f32(*self)
+    }
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<f32> {
+        parcel.get_f32()
+    }
+}
+
+impl Parcelable for f64 {
+    const MIN_SIZE: usize = 8;
+
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+        parcel.put_f64(*self)
+    }
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<f64> {
+        parcel.get_f64()
+    }
+}
+
+/// AIDL `String`, written as UTF-16. Null is a length of -1.
+impl Parcelable for String {
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+        parcel.put_str16(self)
+    }
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<String> {
+        parcel.get_str16()
+    }
+
+    fn write_nullable(value: Option<&String>, parcel: &mut Parcel) -> Result<()> {
+        parcel.put_nullable_str16(value.map(String::as_str))
+    }
+
+    fn read_nullable(parcel: &mut Parcel) -> Result<Option<String>> {
+        parcel.get_nullable_str16()
+    }
+}
+
+/// AIDL `IBinder`. Local objects are written with `put_binder` and handles
+/// with `put_handle`, received objects are read with `get_obj`. Null is a
+/// null binder, so `Option<Object>` is a `@nullable IBinder`.
+impl Parcelable for Object {
+    /// A flat binder object of protocol version 7.
+    const MIN_SIZE: usize = 16;
+
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+        match *self {
+            Object::Handle(handle) => parcel.put_handle(handle),
+            Object::Local(ref object) => parcel.put_binder(object.clone()),
+            Object::Binder(b) if b.is_null() => Err(StatusCode::UnexpectedNull.into()),
+            Object::Binder(_) => {
+                warn!("Received objects of this process can't be sent");
+                Err(StatusCode::BadType.into())
+            }
+        }
+    }
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<Object> {
+        Self::read_nullable(parcel)?.ok_or_else(|| StatusCode::UnexpectedNull.into())
+    }
+
+    fn write_nullable(value: Option<&Object>, parcel: &mut Parcel) -> Result<()> {
+        match value {
+            Some(object) => object.write_to_parcel(parcel),
+            None => parcel.put_null_binder(),
+        }
+    }
+
+    fn read_nullable(parcel: &mut Parcel) -> Result<Option<Object>> {
+        match parcel.get_obj()? {
+            Object::Binder(b) if b.is_null() => Ok(None),
+            object => Ok(Some(object)),
+        }
+    }
+}
+
+/// A `@nullable` value.
+impl<T: Parcelable> Parcelable for Option<T> {
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+        T::write_nullable(self.as_ref(), parcel)
+    }
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<Option<T>> {
+        T::read_nullable(parcel)
+    }
+}
+
+/// An AIDL array or `List`. Null is a count of -1.
+impl<T: Parcelable> Parcelable for Vec<T> {
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+        T::write_array(self, parcel)
+    }
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<Vec<T>> {
+        parcel.get_vec()
+    }
+
+    fn write_nullable(value: Option<&Vec<T>>, parcel: &mut Parcel) -> Result<()> {
+        parcel.put_nullable_vec(value.map(Vec::as_slice))
+    }
+
+    fn read_nullable(parcel: &mut Parcel) -> Result<Option<Vec<T>>> {
+        parcel.get_nullable_vec()
+    }
+}
+
+/// A fixed size AIDL array, written like a `Vec`.
+impl<T: Parcelable, const N: usize> Parcelable for [T; N] {
+    fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+        T::write_array(self, parcel)
+    }
+
+    fn read_from_parcel(parcel: &mut Parcel) -> Result<[T; N]> {
+        let values = Vec::<T>::read_from_parcel(parcel)?;
+        values.try_into().map_err(|values: Vec<T>| {
+            warn!("Expected {} values, got {}", N, values.len());
+            StatusCode::BadValue.into()
+        })
+    }
+}
+
+macro_rules! tuple_parcelable {
+    ($($t:ident $n:tt),+) => {
+        /// The values one after the other.
+        impl<$($t: Parcelable),+> Parcelable for ($($t,)+) {
+            const MIN_SIZE: usize = 0 $(+ $t::MIN_SIZE)+;
+
+            fn write_to_parcel(&self, parcel: &mut Parcel) -> Result<()> {
+                $(self.$n.write_to_parcel(parcel)?;)+
+                Ok(())
+            }
+
+            fn read_from_parcel(parcel: &mut Parcel) -> Result<($($t,)+)> {
+                Ok(($($t::read_from_parcel(parcel)?,)+))
+            }
+        }
+    };
+}
+
+tuple_parcelable!(A 0);
+tuple_parcelable!(A 0, B 1);
+tuple_parcelable!(A 0, B 1, C 2);
+tuple_parcelable!(A 0, B 1, C 2, D 3);
+tuple_parcelable!(A 0, B 1, C 2, D 3, E 4);
+tuple_parcelable!(A 0, B 1, C 2, D 3, E 4, F 5);
+
+#[cfg(test)]
+mod tests {
+    use errors::*;
+    use service::{LocalBinder, Object, Parcel, Parcelable};
+    use std::sync::Arc;
+    use binder::tests::Echo;
+
+    fn round_trip<T: Parcelable>(value: &T) -> T {
+        let mut parcel = Parcel::default();
+        parcel.put(value).unwrap();
+        let value = parcel.get().unwrap();
+        assert_eq!(parcel.data_avail(), 0);
+        value
+    }
+
+    #[test]
+    fn numbers_are_written_in_bulk() {
+        let i32s = vec![0, -1, i32::MIN, i32::MAX, 7];
+        assert_eq!(round_trip(&i32s), i32s);
+        let u32s = vec![0, u32::MAX, 1 << 31];
+        assert_eq!(round_trip(&u32s), u32s);
+        let i64s = vec![i64::MIN, -1, 0, i64::MAX];
+        assert_eq!(round_trip(&i64s), i64s);
+        let u64s: Vec<u64> = (0..100).map(|n| n << 40 | n).collect();
+        assert_eq!(round_trip(&u64s), u64s);
+        assert!(round_trip(&Vec::<i64>::new()).is_empty());
+        assert_eq!(round_trip(&Some(vec![1u32])), Some(vec![1u32]));
+        assert_eq!(round_trip(&None::<Vec<i32>>), None);
+        assert_eq!(round_trip(&[3i64, 4]), [3, 4]);
+
+        // The bulk layout is the one of single values.
+        let mut bulk = Parcel::default();
+        bulk.put(&i64s).unwrap();
+        let mut single = Parcel::default();
+        single.put_i32(i64s.len() as i32).unwrap();
+        for n in &i64s {
+            single.put_i64(*n).unwrap();
+        }
+        assert_eq!(&*bulk, &*single);
+    }
+
+    #[test]
+    fn counts_are_checked_against_the_min_size() {
+        let parcel = |count: i32, bytes: usize| {
+            let mut parcel = Parcel::default();
+            parcel.put_i32(count).unwrap();
+            for _ in 0..bytes / 4 {
+                parcel.put_i32(0).unwrap();
+            }
+            parcel
+        };
+        assert_eq!(parcel(2, 16).get_vec::<i64>().unwrap(), [0, 0]);
+        assert_eq!(parcel(3, 16).get_vec::<i64>().err().unwrap().status(), StatusCode::NotEnoughData as i32);
+        assert_eq!(parcel(4, 16).get_vec::<i32>().unwrap().len(), 4);
+        assert_eq!(parcel(5, 16).get_vec::<u32>().err().unwrap().status(), StatusCode::NotEnoughData as i32);
+        assert_eq!(parcel(3, 16).get_vec::<f64>().err().unwrap().status(), StatusCode::NotEnoughData as i32);
+        // Tuples take the sum of their members.
+        assert_eq!(<(i64, i32)>::MIN_SIZE, 12);
+        assert_eq!(parcel(2, 16).get_vec::<(i64, i32)>().err().unwrap().status(), StatusCode::NotEnoughData as i32);
+        assert_eq!(parcel(2, 16).get_vec::<Object>().err().unwrap().status(), StatusCode::NotEnoughData as i32);
+        // Values may take more than the minimum.
+        assert_eq!(parcel(4, 16).get_vec::<String>().err().unwrap().status(), StatusCode::NotEnoughData as i32);
+    }
+
+    #[test]
+    fn binder_lists() {
+        let local: Arc<dyn LocalBinder> = Arc::new(Echo);
+        let objects = vec![Some(Object::Handle(3)), None, Some(Object::Local(local.clone()))];
+        let mut parcel = Parcel::default();
+        parcel.put(&objects).unwrap();
+        // The null binder isn't an object for the driver.
+        assert_eq!(parcel.offsets().len(), 2);
+        assert_eq!(parcel.objects().len(), 1);
+
+        let read: Vec<Option<Object>> = parcel.get().unwrap();
+        assert_eq!(read.len(), 3);
+        match read[0] {
+            Some(Object::Handle(3)) => (),
+            _ => panic!("Expected handle 3"),
+        }
+        assert!(read[1].is_none());
+        // Without a driver the local object is read back as written.
+        match read[2] {
+            Some(Object::Binder(b)) => assert_eq!(b as usize as u64, local.cookie()),
+            _ => panic!("Expected a local object"),
+        }
+
+        let mut parcel = Parcel::default();
+        parcel.put(&Some(vec![None::<Object>])).unwrap();
+        assert_eq!(parcel.get::<Vec<Object>>().err().unwrap().status(), StatusCode::UnexpectedNull as i32);
+        assert_eq!(Parcel::default().put(&Object::Binder(::std::ptr::null_mut())).err().unwrap().status(),
+                   StatusCode::UnexpectedNull as i32);
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use errors::*;
+use std::fs;
+use std::path::Path;
+use types::BinderUid;
+
+/// The operations on the service manager a `Policy` grants.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Access {
+    Add,
+    Find,
+    List,
+}
+
+#[derive(Debug)]
+struct Rule {
+    /// `None` grants every access.
+    access: Option<Access>,
+    pattern: String,
+    /// `None` matches every uid.
+    uids: Option<Vec<BinderUid>>,
+}
+
+impl Rule {
+    fn matches(&self, access: Access, uid: BinderUid) -> bool {
+        self.access.is_none_or(|a| a == access) && self.uids.as_ref().is_none_or(|u| u.contains(&uid))
+    }
+
+    fn matches_name(&self, name: &str) -> bool {
+        if self.pattern.ends_with('*') {
+            name.starts_with(&self.pattern[..self.pattern.len() - 1])
+        } else {
+            name == self.pattern
+        }
+    }
+}
+
+/// Which uids may add, find and list which services.
+///
+/// A policy file holds one rule per line, `#` starts a comment:
+///
+/// ```text
+/// # access  service             uids
+/// allow add   android.hardware.*  1000,1041
+/// allow find  *                   *
+/// allow list  *                   0,1000
+/// ```
+///
+/// The access is one of `add`, `find`, `list` or `*`. A service pattern
+/// ending with `*` matches by prefix, otherwise the name has to be equal.
+/// Uids are separated by commas, `*` matches any uid. Anything not allowed
+/// by a rule is denied.
+///
+/// Rules match the effective uid of the caller only. Unlike the SELinux
+/// checks of the Android servicemanager there is no security context: the
+/// context manager is registered without `BINDER_SET_CONTEXT_MGR_EXT`, so the
+/// driver never sends `BR_TRANSACTION_SEC_CTX`. The pid of the caller isn't
+/// matched either since the driver reports 0 for one way calls and pids are
+/// recycled.
+#[derive(Debug, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Policy> {
+        let path = path.as_ref();
+        let policy = fs::read_to_string(path).chain_err(|| {
+            format!("Failed to read policy {}", path.display())
+        })?;
+        Policy::parse(&policy).chain_err(|| format!("Invalid policy {}", path.display()))
+    }
+
+    pub fn parse(policy: &str) -> Result<Policy> {
+        let mut rules = vec![];
+        for (n, line) in policy.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let rule = parse_rule(line).chain_err(|| format!("Line {}: {}", n + 1, line))?;
+            rules.push(rule);
+        }
+        Ok(Policy { rules })
+    }
+
+    /// Whether `uid` may `access` the service `name`.
+    pub fn allows(&self, access: Access, name: &str, uid: BinderUid) -> bool {
+        self.rules.iter().any(|r| r.matches(access, uid) && r.matches_name(name))
+    }
+
+    /// Whether `uid` may `access` any service.
+    pub fn allows_any(&self, access: Access, uid: BinderUid) -> bool {
+        self.rules.iter().any(|r| r.matches(access, uid))
+    }
+}
+
+fn parse_rule(line: &str) -> Result<Rule> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 4 || fields[0] != "allow" {
+        return Err("Expected allow <access> <service> <uids>".into());
+    }
+    let access = match fields[1] {
+        "add" => Some(Access::Add),
+        "find" => Some(Access::Find),
+        "list" => Some(Access::List),
+        "*" => None,
+        a => return Err(format!("Invalid access {}", a).into()),
+    };
+    let uids = match fields[3] {
+        "*" => None,
+        uids => {
+            let uids = uids.split(',')
+                .map(|u| u.parse::<BinderUid>())
+                .collect::<::std::result::Result<Vec<_>, _>>()
+                .chain_err(|| format!("Invalid uids {}", uids))?;
+            Some(uids)
+        }
+    };
+    Ok(Rule {
+        access,
+        pattern: fields[2].to_owned(),
+        uids,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Access, Policy};
+
+    #[test]
+    fn rules_are_parsed() {
+        let policy = Policy::parse(
+            "# access service uids\n\
+             allow add   android.hardware.*  1000,1041\n\
+             \n\
+             allow find  *  *  # anyone\n\
+             allow list  media  0\n\
+             allow *     debug  2000\n",
+        ).unwrap();
+        assert_eq!(policy.rules.len(), 4);
+        assert!(policy.allows(Access::Add, "android.hardware.light", 1041));
+        assert!(policy.allows(Access::Add, "android.hardware.", 1000));
+        assert!(!policy.allows(Access::Add, "android.hardware.light", 1042));
+        assert!(!policy.allows(Access::Add, "android.hardwar", 1000));
+        assert!(!policy.allows(Access::Add, "media", 0));
+        assert!(policy.allows(Access::Find, "anything", 12345));
+        assert!(policy.allows(Access::List, "media", 0));
+        assert!(!policy.allows(Access::List, "mediaserver", 0));
+        for &access in &[Access::Add, Access::Find, Access::List] {
+            assert!(policy.allows(access, "debug", 2000));
+        }
+        assert!(policy.allows_any(Access::List, 0));
+        assert!(!policy.allows_any(Access::List, 1000));
+    }
+
+    #[test]
+    fn empty_policies_deny_everything() {
+        for policy in &["", "# nothing\n   \n"] {
+            let policy = Policy::parse(policy).unwrap();
+            assert!(!policy.allows(Access::Find, "x", 0));
+            assert!(!policy.allows_any(Access::List, 0));
+        }
+    }
+
+    #[test]
+    fn malformed_rules_are_rejected() {
+        for line in &[
+            "allow add service",
+            "allow add service 1000 extra",
+            "deny add service 1000",
+            "allow remove service 1000",
+            "allow add service uid",
+            "allow add service 1000,",
+            "allow add service -1",
+            "allow add service 4294967296",
+        ] {
+            let policy = format!("allow find * *\n{}\n", line);
+            let e = Policy::parse(&policy).unwrap_err();
+            assert!(e.to_string().starts_with("Line 2"), "{}: {}", line, e);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use binder::binder::{Binder, CallResult};
+use errors::*;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use super::Parcel;
+
+/// Receiver of the death of a remote object, e.g because its process exited.
+pub trait DeathRecipient: Send + Sync {
+    /// Called from the looper thread that received the notification.
+    fn binder_died(&self, who: &RemoteBinder);
+}
+
+/// An object hosted by another process and referred to by a handle.
+///
+/// This is the counterpart of libbinder's `BpBinder`. A strong and a weak
+/// reference to the handle are taken from the driver when the proxy is
+/// created and dropped together with the last clone, so the handle can't be
+/// recycled while a `RemoteBinder` refers to it. Proxies are cached per
+/// handle: obtaining the same handle twice yields the same proxy.
+#[derive(Clone)]
+pub struct RemoteBinder {
+    proxy: Arc<Proxy>,
+}
+
+pub(crate) struct Proxy {
+    handle: u32,
+    binder: Arc<Binder>,
+    obituaries: Mutex<Obituaries>,
+}
+
+#[derive(Default)]
+struct Obituaries {
+    recipients: Vec<Arc<dyn DeathRecipient>>,
+    /// Set once the death was delivered.
+    sent: bool,
+}
+
+impl RemoteBinder {
+    /// The proxy of `handle`, e.g received as `Object::Handle`, on `binder`.
+    pub fn new(binder: &Arc<Binder>, handle: u32) -> Result<RemoteBinder> {
+        let mut proxies = binder.proxies().lock().unwrap();
+        if let Some(proxy) = proxies.get(&handle).and_then(|p| p.upgrade()) {
+            return Ok(RemoteBinder { proxy });
+        }
+
+        binder.acquire(handle)?;
+        let proxy = Arc::new(Proxy {
+            handle,
+            binder: binder.clone(),
+            obituaries: Mutex::new(Obituaries::default()),
+        });
+        proxies.insert(handle, Arc::downgrade(&proxy));
+        Ok(RemoteBinder { proxy })
+    }
+
+    pub(crate) fn from_proxy(proxy: Arc<Proxy>) -> RemoteBinder {
+        RemoteBinder { proxy }
+    }
+
+    pub fn handle(&self) -> u32 {
+        self.proxy.handle
+    }
+
+    /// The binder the handle belongs to.
+    pub fn binder(&self) -> &Arc<Binder> {
+        &self.proxy.binder
+    }
+
+    /// Send transaction `code` with `data` to the object.
+    pub fn transact(&self, code: u32, data: &Parcel, flags: u32) -> Result<CallResult> {
+        self.proxy.binder.call(data, self.proxy.handle, code, flags)
+    }
+
+    /// Call `recipient` once the object dies.
+    ///
+    /// Fails with `StatusCode::DeadObject` if the death was already delivered.
+    pub fn link_to_death(&self, recipient: Arc<dyn DeathRecipient>) -> Result<()> {
+        let mut obituaries = self.proxy.obituaries.lock().unwrap();
+        if obituaries.sent {
+            return Err(StatusCode::DeadObject.into());
+        }
+        if obituaries.recipients.is_empty() {
+            self.proxy.binder.request_death_notification(self.proxy.handle)?;
+        }
+        obituaries.recipients.push(recipient);
+        Ok(())
+    }
+
+    /// Remove a `recipient` added with `link_to_death`.
+    ///
+    /// Fails with `StatusCode::NameNotFound` if the recipient isn't linked.
+    pub fn unlink_to_death(&self, recipient: &Arc<dyn DeathRecipient>) -> Result<()> {
+        let mut obituaries = self.proxy.obituaries.lock().unwrap();
+        if obituaries.sent {
+            return Err(StatusCode::DeadObject.into());
+        }
+        let index = obituaries
+            .recipients
+            .iter()
+            .position(|r| same_recipient(r, recipient))
+            .ok_or_else(|| Error::from(StatusCode::NameNotFound))?;
+        obituaries.recipients.remove(index);
+        if obituaries.recipients.is_empty() {
+            self.proxy.binder.clear_death_notification(self.proxy.handle)?;
+        }
+        Ok(())
+    }
+
+    /// Deliver the death of the object to the linked recipients.
+    pub(crate) fn send_obituary(&self) {
+        let recipients = {
+            let mut obituaries = self.proxy.obituaries.lock().unwrap();
+            if obituaries.sent {
+                return;
+            }
+            obituaries.sent = true;
+            mem::take(&mut obituaries.recipients)
+        };
+        if recipients.is_empty() {
+            return;
+        }
+        self.proxy.binder.clear_death_notification(self.proxy.handle).unwrap_or_else(|e| {
+            error!("Failed to clear death notification of handle {}: {}", self.proxy.handle, e);
+        });
+        for recipient in recipients {
+            recipient.binder_died(self);
+        }
+    }
+}
+
+impl PartialEq for RemoteBinder {
+    fn eq(&self, other: &RemoteBinder) -> bool {
+        Arc::ptr_eq(&self.proxy, &other.proxy)
+    }
+}
+
+impl Eq for RemoteBinder {}
+
+impl fmt::Debug for RemoteBinder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RemoteBinder {{ handle: {} }}", self.proxy.handle)
+    }
+}
+
+fn same_recipient(a: &Arc<dyn DeathRecipient>, b: &Arc<dyn DeathRecipient>) -> bool {
+    &**a as *const dyn DeathRecipient as *const () == &**b as *const dyn DeathRecipient as *const ()
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let linked = {
+            let obituaries = self.obituaries.lock().unwrap();
+            !obituaries.sent && !obituaries.recipients.is_empty()
+        };
+        if linked {
+            self.binder.clear_death_notification(self.handle).unwrap_or_else(|e| {
+                error!("Failed to clear death notification of handle {}: {}", self.handle, e);
+            });
+        }
+        {
+            // A new proxy for the handle may have replaced this one already.
+            let mut proxies = self.binder.proxies().lock().unwrap();
+            if let Entry::Occupied(e) = proxies.entry(self.handle) {
+                if e.get().upgrade().is_none() {
+                    e.remove();
+                }
+            }
+        }
+        self.binder.release(self.handle).unwrap_or_else(|e| {
+            error!("Failed to release handle {}: {}", self.handle, e);
+        });
+    }
+}
@@ -13,32 +13,114 @@
 // limitations under the License.
 
 use errors::*;
-use binder::binder::{CallResult, Reply, Binder};
-use super::Parcel;
+use binder::binder::{CallResult, Reply};
+use std::sync::Arc;
+use super::{DeathRecipient, Parcel, RemoteBinder};
 
+#[derive(Clone)]
 pub struct Service {
-    handle: u32,
-    binder: Binder,
+    remote: RemoteBinder,
 }
 
 impl Service {
-    pub fn new(h: u32, b: Binder) -> Service {
-        Service {
-            handle: h,
-            binder: b,
-        }
+    pub fn new(remote: RemoteBinder) -> Service {
+        Service { remote }
+    }
+
+    /// The proxy of the service object.
+    pub fn remote(&self) -> &RemoteBinder {
+        &self.remote
+    }
+
+    /// Call `recipient` once the service dies. See `RemoteBinder::link_to_death`.
+    pub fn link_to_death(&self, recipient: Arc<dyn DeathRecipient>) -> Result<()> {
+        self.remote.link_to_death(recipient)
+    }
+
+    pub fn unlink_to_death(&self, recipient: &Arc<dyn DeathRecipient>) -> Result<()> {
+        self.remote.unlink_to_death(recipient)
     }
 
+    /// Send transaction `code` with `parcel` and return the reply. A status
+    /// code reply fails with `ErrorKind::Status`. One way calls return an
+    /// empty parcel.
     pub fn call(&self, code: u32, parcel: &Parcel, flags: u32) -> Result<Parcel> {
-        let r = self.binder.call(&parcel, self.handle, code, flags)?;
-        if let CallResult::Reply(r) = r {
-            match r {
-                Reply::Data(r) => {
-                    return Ok(Parcel::from_buf(&r));
+        match self.remote.transact(code, parcel, flags)? {
+            CallResult::Reply(Reply::Data(r)) => Ok(r),
+            CallResult::Reply(Reply::StatusCode(c)) => Err(ErrorKind::Status(c as i32).into()),
+            CallResult::Noop => Ok(Parcel::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use binder::Binder;
+    use binder::mock::MockDriver;
+    use binder::tests::context_manager;
+    use errors::*;
+    use service::{LocalBinder, Parcel, RemoteBinder};
+    use std::sync::Mutex;
+    use std::sync::Arc;
+    use std::sync::mpsc::{channel, Sender};
+    use std::time::Duration;
+    use super::Service;
+    use types::TransactionFlags;
+
+    /// Doubles the `i32` sent with code 1, denies code 2 and forwards the
+    /// `i32` of one way code 3.
+    struct Target(Mutex<Sender<i32>>);
+
+    impl LocalBinder for Target {
+        fn on_transact(&self, code: u32, data: &mut Parcel, reply: &mut Parcel, _flags: u32) -> Result<()> {
+            match code {
+                1 => reply.put_i32(data.get_i32()? * 2),
+                2 => Err(StatusCode::PermissionDenied.into()),
+                3 => {
+                    self.0.lock().unwrap().send(data.get_i32()?).unwrap();
+                    Ok(())
                 }
-                _ => unimplemented!(),
+                _ => Err(StatusCode::UnknownTransaction.into()),
             }
         }
-        unimplemented!()
+    }
+
+    fn service() -> (Service, ::std::sync::mpsc::Receiver<i32>) {
+        let driver = MockDriver::new();
+        let (tx, rx) = channel();
+        context_manager(&driver, Arc::new(Target(Mutex::new(tx))));
+        let client = Arc::new(Binder::with_transport(Box::new(driver.open())).unwrap());
+        (Service::new(RemoteBinder::new(&client, 0).unwrap()), rx)
+    }
+
+    #[test]
+    fn call_returns_the_reply() {
+        let (service, _) = service();
+        let mut parcel = Parcel::default();
+        parcel.put_i32(21).unwrap();
+        assert_eq!(service.call(1, &parcel, 0).unwrap().get_i32().unwrap(), 42);
+    }
+
+    #[test]
+    fn status_replies_are_errors() {
+        let (service, _) = service();
+        for &(code, status) in &[(2, StatusCode::PermissionDenied), (9, StatusCode::UnknownTransaction)] {
+            match *service.call(code, &Parcel::default(), 0).unwrap_err().kind() {
+                ErrorKind::Status(s) => assert_eq!(s, status as i32),
+                ref e => panic!("Unexpected {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn one_way_calls_return_empty_parcels() {
+        let (service, rx) = service();
+        let mut parcel = Parcel::default();
+        parcel.put_i32(7).unwrap();
+        let reply = service.call(3, &parcel, TransactionFlags::ONE_WAY as u32).unwrap();
+        assert!(reply.is_empty());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 7);
+        // Failures of one way calls aren't reported.
+        service.call(2, &parcel, TransactionFlags::ONE_WAY as u32).unwrap();
     }
 }
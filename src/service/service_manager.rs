@@ -12,32 +12,169 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use binder::ProcessState;
 use binder::binder::{CallResult, Reply, Binder};
 use errors::*;
+use std::cmp::min;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::vec;
 use types::*;
+use utils::system_property;
 use super::parcel::{Parcel, Object};
-use super::Service;
+use super::service_notification::{ServiceCallback, ServiceNotification};
+use super::{LocalBinder, RemoteBinder, Service};
 
 const BINDER_SERVICE_MANAGER: u32 = 0;
 
-const SVC_MGR_GET_SERVICE: u32 = 1;
-const _SVC_MGR_CHECK_SERVICE: u32 = 2;
-const SVC_MGR_ADD_SERVICE: u32 = 3;
-const SVC_MGR_LIST_SERVICES: u32 = 4;
+pub(crate) const SVC_MGR_GET_SERVICE: u32 = 1;
+pub(crate) const SVC_MGR_CHECK_SERVICE: u32 = 2;
+pub(crate) const SVC_MGR_ADD_SERVICE: u32 = 3;
+pub(crate) const SVC_MGR_LIST_SERVICES: u32 = 4;
 
-const INTERFACE_SERVICE_MANAGER: &str = "android.os.IServiceManager";
+pub(crate) const INTERFACE_SERVICE_MANAGER: &str = "android.os.IServiceManager";
 
+/// Longest interval between two lookups in `wait_for_service`.
+const MAX_WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub const DUMP_FLAG_PRIORITY_CRITICAL: i32 = 1 << 0;
+pub const DUMP_FLAG_PRIORITY_HIGH: i32 = 1 << 1;
+pub const DUMP_FLAG_PRIORITY_NORMAL: i32 = 1 << 2;
+pub const DUMP_FLAG_PRIORITY_DEFAULT: i32 = 1 << 3;
+pub const DUMP_FLAG_PRIORITY_ALL: i32 = DUMP_FLAG_PRIORITY_CRITICAL | DUMP_FLAG_PRIORITY_HIGH |
+    DUMP_FLAG_PRIORITY_NORMAL | DUMP_FLAG_PRIORITY_DEFAULT;
+pub const DUMP_FLAG_PROTO: i32 = 1 << 4;
+
+/// `Stability::Level::SYSTEM` sent after binder objects.
+const STABILITY_SYSTEM: i32 = 0b00_1100;
+/// Wire format version of the binder stability category of Android 12.
+const STABILITY_WIRE_FORMAT_VERSION: i32 = 1;
+
+/// The protocol spoken by the service manager.
+///
+/// The AIDL service manager of Android 11 and later sends a status header
+/// with each reply and expects a stability level after each binder object.
+/// The transaction codes beyond `listServices` differ between releases.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ServiceManagerFlavor {
+    /// The C service manager of Android 10 and before and `ServiceManagerServer`.
+    Legacy,
+    Android11,
+    Android12,
+    /// Android 13 and later.
+    Android13,
+}
+
+/// Calls of `IServiceManager`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Method {
+    GetService,
+    CheckService,
+    AddService,
+    ListServices,
+    RegisterForNotifications,
+    UnregisterForNotifications,
+    IsDeclared,
+    GetDeclaredInstances,
+    UpdatableViaApex,
+    RegisterClientCallback,
+    TryUnregisterService,
+    GetServiceDebugInfo,
+}
+
+impl ServiceManagerFlavor {
+    /// The transaction code of `method`, if supported.
+    fn code(self, method: Method) -> Option<u32> {
+        use self::Method::*;
+        use self::ServiceManagerFlavor::*;
+        match (self, method) {
+            (_, GetService) => Some(SVC_MGR_GET_SERVICE),
+            (_, CheckService) => Some(SVC_MGR_CHECK_SERVICE),
+            (_, AddService) => Some(SVC_MGR_ADD_SERVICE),
+            (_, ListServices) => Some(SVC_MGR_LIST_SERVICES),
+            (Legacy, _) => None,
+            (_, RegisterForNotifications) => Some(5),
+            (_, UnregisterForNotifications) => Some(6),
+            (_, IsDeclared) => Some(7),
+            (Android11, RegisterClientCallback) => Some(8),
+            (Android11, TryUnregisterService) => Some(9),
+            (Android11, _) => None,
+            (_, GetDeclaredInstances) => Some(8),
+            (_, UpdatableViaApex) => Some(9),
+            (Android12, RegisterClientCallback) => Some(10),
+            (Android12, TryUnregisterService) => Some(11),
+            (Android12, GetServiceDebugInfo) => Some(12),
+            (_, RegisterClientCallback) => Some(12),
+            (_, TryUnregisterService) => Some(13),
+            (_, GetServiceDebugInfo) => Some(14),
+        }
+    }
+
+    /// The stability written after binder objects.
+    fn stability(self) -> Option<i32> {
+        match self {
+            ServiceManagerFlavor::Legacy => None,
+            ServiceManagerFlavor::Android12 => Some(STABILITY_WIRE_FORMAT_VERSION | STABILITY_SYSTEM << 24),
+            _ => Some(STABILITY_SYSTEM),
+        }
+    }
+}
+
+/// A service registered with the service manager and the process hosting it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceDebugInfo {
+    pub name: String,
+    pub debug_pid: i32,
+}
+
+/// Client of the context manager. Clones share the binder of the process.
+#[derive(Clone)]
 pub struct ServiceManager {
-    binder: Binder,
+    binder: Arc<Binder>,
+    flavor: ServiceManagerFlavor,
 }
 
 impl ServiceManager {
+    /// The service manager reached via the binder of this process, see `ProcessState`.
     pub fn new() -> Result<ServiceManager> {
-        let s: ServiceManager = Binder::new()?.into();
+        ServiceManager::detect(ProcessState::binder()?)
+    }
+
+    /// The service manager of the context of `device`, e.g `/dev/vndbinder`.
+    pub fn with_device(device: &str) -> Result<ServiceManager> {
+        ServiceManager::detect(ProcessState::context(device)?)
+    }
+
+    /// The service manager reached via `binder`, speaking the legacy protocol.
+    pub fn with_binder(binder: Arc<Binder>) -> ServiceManager {
+        ServiceManager::with_flavor(binder, ServiceManagerFlavor::Legacy)
+    }
+
+    /// The service manager reached via `binder`, speaking `flavor`.
+    pub fn with_flavor(binder: Arc<Binder>, flavor: ServiceManagerFlavor) -> ServiceManager {
+        ServiceManager { binder, flavor }
+    }
+
+    /// The service manager reached via `binder`. The flavor is detected by
+    /// asking for the interface descriptor, which only the AIDL service
+    /// manager answers, and from the SDK version of the system.
+    pub fn detect(binder: Arc<Binder>) -> Result<ServiceManager> {
+        let mut s = ServiceManager::with_binder(binder);
         s.ping()?;
+        s.flavor = s.detect_flavor()?;
+        info!("Service manager speaks {:?} protocol", s.flavor);
         Ok(s)
     }
 
+    /// The binder transactions to the service manager and local objects are handled by.
+    pub fn binder(&self) -> &Arc<Binder> {
+        &self.binder
+    }
+
+    pub fn flavor(&self) -> ServiceManagerFlavor {
+        self.flavor
+    }
+
     fn ping(&self) -> Result<()> {
         info!("Pingging service manager");
         let d = Parcel::default();
@@ -50,89 +187,504 @@ impl ServiceManager {
         Ok(())
     }
 
-    pub fn get_service(self, name: &str) -> Result<Service> {
+    fn detect_flavor(&self) -> Result<ServiceManagerFlavor> {
+        let d = Parcel::default();
+        let interface = match self.binder.call(&d, BINDER_SERVICE_MANAGER, Transaction::Interface as u32, 0)? {
+            CallResult::Reply(Reply::Data(mut r)) => r.get_str16().ok(),
+            _ => None,
+        };
+        if interface.as_deref() != Some(INTERFACE_SERVICE_MANAGER) {
+            return Ok(ServiceManagerFlavor::Legacy);
+        }
+        let sdk = system_property("ro.build.version.sdk").and_then(|v| v.parse::<u32>().ok());
+        Ok(match sdk {
+            Some(30) => ServiceManagerFlavor::Android11,
+            Some(31) | Some(32) => ServiceManagerFlavor::Android12,
+            _ => ServiceManagerFlavor::Android13,
+        })
+    }
+
+    /// Send `method` with the arguments written by `args` and return the
+    /// reply positioned after the status header.
+    fn transact<F>(&self, method: Method, args: F) -> Result<Parcel>
+    where
+        F: FnOnce(&mut Parcel) -> Result<()>,
+    {
+        let code = self.flavor.code(method).ok_or_else(|| {
+            warn!("{:?} isn't supported by the {:?} service manager", method, self.flavor);
+            Error::from(StatusCode::InvalidOperation)
+        })?;
         let mut p = Parcel::default();
-        p.put_interface_token(INTERFACE_SERVICE_MANAGER)?;
-        p.put_str16(name)?;
-        let r = self.binder.call(
-            &p,
-            BINDER_SERVICE_MANAGER,
-            SVC_MGR_GET_SERVICE,
-            0,
-        )?;
+        match self.flavor {
+            ServiceManagerFlavor::Legacy => p.put_interface_token(INTERFACE_SERVICE_MANAGER)?,
+            _ => p.put_aidl_interface_token(INTERFACE_SERVICE_MANAGER)?,
+        }
+        args(&mut p)?;
 
-        if let CallResult::Reply(r) = r {
-            match r {
-                Reply::Data(d) => {
-                    let mut p = Parcel::from_buf(&d);
-                    info!("Received parcel with {} bytes", p.len());
-                    match p.get_obj()? {
-                        Object::Handle(h) => {
-                            debug!("Received handle {}", h);
-                            return Ok(Service::new(h, self.binder));
-                        },
-                        _ => unimplemented!(),
-                    }
-                },
-                _ => unimplemented!(),
+        match self.binder.call(&p, BINDER_SERVICE_MANAGER, code, 0)? {
+            CallResult::Reply(Reply::Data(mut r)) => {
+                info!("Received parcel with {} bytes", r.len());
+                if self.flavor != ServiceManagerFlavor::Legacy {
+                    r.get_status()?;
+                }
+                Ok(r)
+            }
+            CallResult::Reply(Reply::StatusCode(c)) => {
+                debug!("Received status code {} for {:?}", c as i32, method);
+                Err(ErrorKind::Status(c as i32).into())
             }
+            CallResult::Noop => Err(format!("Invalid reply for {:?}", method).into()),
         }
-        Err("Invalid reply for get service call".into())
     }
 
-    pub fn add_service(self, name: &str, allow_isolated: bool) -> Result<()> {
-        let mut p = Parcel::default();
-        p.put_interface_token(INTERFACE_SERVICE_MANAGER)?;
-        p.put_str16(name)?;
-        // TODO: Adding services is not yet functional. Transmitting binder handles
-        // require some ioctrls that are done from Parcel in libbinder. This part is missing here.
-        p.put_binder(0xABABABAB as BinderPtr, 0xCACACACA as BinderPtr)?; 
-        p.put_i32(if allow_isolated { 1 } else { 0 })?;
-        match self.binder.call(&p, BINDER_SERVICE_MANAGER, SVC_MGR_ADD_SERVICE, 0) {
-            Ok(CallResult::Reply(Reply::StatusCode(c))) => {
-                warn!("Received status code {}", c);
-                return Err("Failed to add service".into());
-            },
-            Ok(CallResult::Reply(Reply::Data(d))) => {
-                info!("Received data:");
-                hex!(&d);
-            },
-            _ => unimplemented!(),
+    pub fn get_service(&self, name: &str) -> Result<Service> {
+        let r = self.transact(Method::GetService, |p| p.put_str16(name))?;
+        self.read_service(r)?.ok_or_else(|| {
+            warn!("Service {} not found", name);
+            StatusCode::NameNotFound.into()
+        })
+    }
+
+    /// Look up `name` without failing if it isn't registered. Like libbinder
+    /// any status code the legacy service manager replies with means not found.
+    pub fn check_service(&self, name: &str) -> Result<Option<Service>> {
+        match self.transact(Method::CheckService, |p| p.put_str16(name)) {
+            Ok(r) => self.read_service(r),
+            Err(Error(ErrorKind::Status(_), _)) if self.flavor == ServiceManagerFlavor::Legacy => Ok(None),
+            Err(e) => Err(e),
         }
+    }
+
+    /// Wait up to `timeout` for `name` to be registered. Fails with `StatusCode::TimedOut`.
+    ///
+    /// The AIDL service manager notifies about the registration if this
+    /// process has a looper thread. Otherwise and with the legacy service
+    /// manager it is asked again in growing intervals.
+    pub fn wait_for_service(&self, name: &str, timeout: Duration) -> Result<Service> {
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = mpsc::channel();
+        let _notification = match self.flavor {
+            ServiceManagerFlavor::Legacy => None,
+            _ => {
+                let tx = Mutex::new(tx.clone());
+                Some(self.register_for_notifications(name, move |_, service| {
+                    tx.lock().unwrap().send(service).ok();
+                })?)
+            }
+        };
+
+        let mut interval = Duration::from_millis(10);
+        loop {
+            if let Some(service) = self.check_service(name)? {
+                return Ok(service);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("Timeout waiting for service {}", name);
+                return Err(StatusCode::TimedOut.into());
+            }
+            info!("Waiting for service {}", name);
+            if let Ok(service) = rx.recv_timeout(min(interval, deadline - now)) {
+                return Ok(service);
+            }
+            interval = min(interval * 2, MAX_WAIT_INTERVAL);
+        }
+    }
+
+    /// Call `callback` with the name and the service each time `name` is
+    /// registered, including right away if it already is. Only supported by
+    /// the AIDL service manager.
+    ///
+    /// Services hosted by this process can't be wrapped in a `Service` and
+    /// their registration is ignored.
+    ///
+    /// The callback is invoked on a looper thread of this process, see
+    /// `Binder::start_thread_pool`. It is unregistered when the returned
+    /// `ServiceNotification` is dropped.
+    pub fn register_for_notifications<F>(&self, name: &str, callback: F) -> Result<ServiceNotification>
+    where
+        F: Fn(&str, Service) + Send + Sync + 'static,
+    {
+        let callback = Arc::new(ServiceCallback::new(&self.binder, Box::new(callback)));
+        self.transact(Method::RegisterForNotifications, |p| {
+            p.put_str16(name)?;
+            self.put_binder(p, callback.clone())
+        })?;
+        Ok(ServiceNotification::new(self.clone(), name, callback))
+    }
+
+    pub(crate) fn unregister_for_notifications(&self, name: &str, callback: Arc<ServiceCallback>) -> Result<()> {
+        self.transact(Method::UnregisterForNotifications, |p| {
+            p.put_str16(name)?;
+            self.put_binder(p, callback)
+        })?;
         Ok(())
     }
 
+    /// The service referenced by a get or check service reply, if any.
+    fn read_service(&self, mut p: Parcel) -> Result<Option<Service>> {
+        let object = p.get_obj()?;
+        if self.flavor.stability().is_some() {
+            p.get_i32()?; // stability
+        }
+        match object {
+            Object::Handle(h) => {
+                debug!("Received handle {}", h);
+                Ok(Some(Service::new(RemoteBinder::new(&self.binder, h)?)))
+            }
+            Object::Binder(b) if b.is_null() => Ok(None),
+            Object::Binder(_) | Object::Local(_) => Err("Services of this process are not supported".into()),
+        }
+    }
+
+    /// Write a reference to the local `object` as the service manager expects it.
+    fn put_binder(&self, p: &mut Parcel, object: Arc<dyn LocalBinder>) -> Result<()> {
+        p.put_binder(object)?;
+        match self.flavor.stability() {
+            Some(stability) => p.put_i32(stability),
+            None => Ok(()),
+        }
+    }
+
+    /// Publish the local `object` under `name`.
+    ///
+    /// The object is served by the `Binder` of this service manager as long
+    /// as it is around. `dump_priority` is a combination of the
+    /// `DUMP_FLAG_PRIORITY_*` flags.
+    pub fn add_service(&self, name: &str, object: Arc<dyn LocalBinder>, allow_isolated: bool, dump_priority: i32) -> Result<()> {
+        let mut r = self.transact(Method::AddService, |p| {
+            p.put_str16(name)?;
+            self.put_binder(p, object)?;
+            p.put_i32(if allow_isolated { 1 } else { 0 })?;
+            p.put_i32(dump_priority)
+        })?;
+        if self.flavor != ServiceManagerFlavor::Legacy {
+            return Ok(());
+        }
+        match r.get_i32()? {
+            0 => Ok(()),
+            c => {
+                warn!("Adding {} failed with {}", name, c);
+                Err(ErrorKind::Status(c).into())
+            }
+        }
+    }
+
+    /// The names of all registered services.
     pub fn list_services(&self) -> Result<Vec<String>> {
-        let mut result = Vec::new();
-
-        for n in 0..::std::u32::MAX {
-            info!("Getting service number {}", n);
-            let mut data = Parcel::default();
-            data.put_interface_token(INTERFACE_SERVICE_MANAGER)?;
-            data.put_u32(n)?;
-            if let Ok(r) = self.binder.call(&data, BINDER_SERVICE_MANAGER, SVC_MGR_LIST_SERVICES, 0) {
-                if let CallResult::Reply(r) = r {
-                    match r {
-                        Reply::Data(d) => {
-                            let mut p = Parcel::from_buf(&d);
-                            let svc =  p.get_str16()?;
-                            debug!("service: {}", svc);
-                            result.push(svc);
-                        }
-                        Reply::StatusCode(_) => break,
-                    }
-                }
-            } else {
-                info!("Finished after {} services", result.len());
-                break;
+        self.list_services_with_priority(DUMP_FLAG_PRIORITY_ALL)
+    }
+
+    /// The names of the services registered with any of the
+    /// `DUMP_FLAG_PRIORITY_*` flags in `dump_priority`.
+    pub fn list_services_with_priority(&self, dump_priority: i32) -> Result<Vec<String>> {
+        self.services(dump_priority)?.collect()
+    }
+
+    /// Iterate the names of the services registered with any of the
+    /// `DUMP_FLAG_PRIORITY_*` flags in `dump_priority`.
+    ///
+    /// The AIDL service manager sends all names at once. The legacy one is
+    /// asked for one name per step, until it replies with a status instead.
+    pub fn services(&self, dump_priority: i32) -> Result<ServiceList> {
+        let state = match self.flavor {
+            ServiceManagerFlavor::Legacy => ListState::Next(0),
+            _ => {
+                let mut r = self.transact(Method::ListServices, |p| p.put_i32(dump_priority))?;
+                ListState::Received(get_str16_array(&mut r)?.into_iter())
             }
+        };
+        Ok(ServiceList {
+            service_manager: self.clone(),
+            dump_priority,
+            state,
+        })
+    }
+
+    /// The name at `index` of the legacy service manager's list, or `None` at its end.
+    ///
+    /// The C service manager replies with -1 both at the end of the list and
+    /// if listing is denied, `ServiceManagerServer` replies with
+    /// `StatusCode::NameNotFound` at the end. -1 is taken as denial for the
+    /// first name only.
+    fn list_service(&self, index: u32, dump_priority: i32) -> Result<Option<String>> {
+        let r = self.transact(Method::ListServices, |p| {
+            p.put_u32(index)?;
+            p.put_i32(dump_priority)
+        });
+        match r {
+            Ok(mut r) => r.get_str16().map(Some),
+            Err(Error(ErrorKind::Status(c), _)) if c == StatusCode::NameNotFound as i32 => Ok(None),
+            Err(Error(ErrorKind::Status(c), _)) if c == StatusCode::PermissionDenied as i32 && index > 0 => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Have the service manager tell `callback`, an `IClientCallback`, when
+    /// the service `object` published as `name` gains or loses its clients.
+    pub(crate) fn register_client_callback(&self, name: &str, object: Arc<dyn LocalBinder>, callback: Arc<dyn LocalBinder>) -> Result<()> {
+        self.transact(Method::RegisterClientCallback, |p| {
+            p.put_str16(name)?;
+            self.put_binder(p, object)?;
+            self.put_binder(p, callback)
+        })?;
+        Ok(())
+    }
+
+    /// Remove the service `object` published as `name`. The service manager
+    /// refuses if the service has clients.
+    pub(crate) fn try_unregister_service(&self, name: &str, object: Arc<dyn LocalBinder>) -> Result<()> {
+        self.transact(Method::TryUnregisterService, |p| {
+            p.put_str16(name)?;
+            self.put_binder(p, object)
+        })?;
+        Ok(())
+    }
+
+    /// Whether `name` is declared in a VINTF manifest.
+    pub fn is_declared(&self, name: &str) -> Result<bool> {
+        let mut r = self.transact(Method::IsDeclared, |p| p.put_str16(name))?;
+        Ok(r.get_i32()? != 0)
+    }
+
+    /// The instances of `interface` declared in a VINTF manifest.
+    pub fn get_declared_instances(&self, interface: &str) -> Result<Vec<String>> {
+        let mut r = self.transact(Method::GetDeclaredInstances, |p| p.put_str16(interface))?;
+        get_str16_array(&mut r)
+    }
+
+    /// The APEX `name` can be updated with, if any.
+    pub fn updatable_via_apex(&self, name: &str) -> Result<Option<String>> {
+        let mut r = self.transact(Method::UpdatableViaApex, |p| p.put_str16(name))?;
+        r.get_nullable_str16()
+    }
+
+    /// The registered services and the processes hosting them.
+    pub fn get_service_debug_info(&self) -> Result<Vec<ServiceDebugInfo>> {
+        let mut r = self.transact(Method::GetServiceDebugInfo, |_| Ok(()))?;
+        // Each parcelable has at least the null marker and its size
+        let n = r.get_array_length(8)?.unwrap_or(0);
+        let mut result = Vec::with_capacity(n);
+        for _ in 0..n {
+            if r.get_i32()? == 0 {
+                return Err(StatusCode::UnexpectedNull.into());
+            }
+            // Parcelables start with their size to skip fields added later
+            let start = r.data_position();
+            let size = r.get_i32()?;
+            if size < 4 {
+                warn!("Invalid parcelable size {}", size);
+                return Err(StatusCode::BadValue.into());
+            }
+            let name = r.get_str16()?;
+            let debug_pid = r.get_i32()?;
+            if r.data_position() - start > size as usize {
+                warn!("Parcelable exceeds its size {}", size);
+                return Err(StatusCode::BadValue.into());
+            }
+            r.seek(start, size)?;
+            result.push(ServiceDebugInfo { name, debug_pid });
         }
         Ok(result)
     }
 }
 
+/// Names of services, see `ServiceManager::services`. Iteration ends after an error.
+pub struct ServiceList {
+    service_manager: ServiceManager,
+    dump_priority: i32,
+    state: ListState,
+}
+
+enum ListState {
+    /// All names received at once.
+    Received(vec::IntoIter<String>),
+    /// The index of the next name to ask the legacy service manager for.
+    Next(u32),
+    Done,
+}
+
+impl Iterator for ServiceList {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        let index = match self.state {
+            ListState::Received(ref mut names) => return names.next().map(Ok),
+            ListState::Next(index) => index,
+            ListState::Done => return None,
+        };
+        match self.service_manager.list_service(index, self.dump_priority) {
+            Ok(Some(name)) => {
+                debug!("Service {}: {}", index, name);
+                self.state = ListState::Next(index + 1);
+                Some(Ok(name))
+            }
+            Ok(None) => {
+                info!("Finished after {} services", index);
+                self.state = ListState::Done;
+                None
+            }
+            Err(e) => {
+                self.state = ListState::Done;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Read a `String[]`. A null array is read as empty.
+fn get_str16_array(p: &mut Parcel) -> Result<Vec<String>> {
+    // Each string has at least its length and the zero termination
+    let n = p.get_array_length(8)?.unwrap_or(0);
+    let mut result = Vec::with_capacity(n);
+    for _ in 0..n {
+        result.push(p.get_str16()?);
+    }
+    Ok(result)
+}
+
 impl Into<ServiceManager> for Binder {
     fn into(self) -> ServiceManager {
-        ServiceManager { binder: self }
+        ServiceManager::with_binder(Arc::new(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use binder::Binder;
+    use binder::mock::MockDriver;
+    use binder::tests::context_manager;
+    use errors::*;
+    use service::{LocalBinder, Parcel};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use super::{Method, ServiceManager, ServiceManagerFlavor, SVC_MGR_CHECK_SERVICE, SVC_MGR_GET_SERVICE,
+                STABILITY_SYSTEM};
+
+    /// An AIDL service manager without services replying like libbinder.
+    struct Empty;
+
+    impl LocalBinder for Empty {
+        fn on_transact(&self, code: u32, _data: &mut Parcel, reply: &mut Parcel, _flags: u32) -> Result<()> {
+            reply.put_i32(0)?; // status header
+            if code == SVC_MGR_GET_SERVICE || code == SVC_MGR_CHECK_SERVICE {
+                reply.put_null_binder()?;
+                reply.put_i32(STABILITY_SYSTEM)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn null_services_are_not_found() {
+        let driver = MockDriver::new();
+        let _manager = context_manager(&driver, Arc::new(Empty));
+        let binder = Arc::new(Binder::with_transport(Box::new(driver.open())).unwrap());
+        let sm = ServiceManager::with_flavor(binder, ServiceManagerFlavor::Android13);
+
+        assert!(sm.check_service("nope").unwrap().is_none());
+        assert_eq!(sm.get_service("nope").err().unwrap().status(), StatusCode::NameNotFound as i32);
+        let e = sm.wait_for_service("nope", Duration::from_millis(50)).err().unwrap();
+        assert_eq!(e.status(), StatusCode::TimedOut as i32);
+    }
+
+    #[test]
+    fn code_tables() {
+        use super::Method::*;
+        use super::ServiceManagerFlavor::*;
+        let methods = [
+            GetService,
+            CheckService,
+            AddService,
+            ListServices,
+            RegisterForNotifications,
+            UnregisterForNotifications,
+            IsDeclared,
+            GetDeclaredInstances,
+            UpdatableViaApex,
+            RegisterClientCallback,
+            TryUnregisterService,
+            GetServiceDebugInfo,
+        ];
+        let codes = |flavor: ServiceManagerFlavor| -> Vec<Option<u32>> {
+            methods.iter().map(|m| flavor.code(*m)).collect()
+        };
+        let n = None;
+        assert_eq!(codes(Legacy), [Some(1), Some(2), Some(3), Some(4), n, n, n, n, n, n, n, n]);
+        assert_eq!(
+            codes(Android11),
+            [Some(1), Some(2), Some(3), Some(4), Some(5), Some(6), Some(7), n, n, Some(8), Some(9), n]
+        );
+        assert_eq!(
+            codes(Android12),
+            (1..13).map(Some).collect::<Vec<_>>()
+        );
+        // getUpdatableNames and getConnectionInfo take 10 and 11.
+        assert_eq!(
+            codes(Android13),
+            [1, 2, 3, 4, 5, 6, 7, 8, 9, 12, 13, 14].iter().cloned().map(Some).collect::<Vec<_>>()
+        );
+    }
+
+    /// An AIDL service manager recording the codes it receives. `isDeclared`
+    /// is answered with a reply header, `updatableViaApex` without and any
+    /// other call with a service specific error.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<u32>>);
+
+    impl LocalBinder for Recorder {
+        fn on_transact(&self, code: u32, data: &mut Parcel, reply: &mut Parcel, _flags: u32) -> Result<()> {
+            data.enforce_aidl_interface(super::INTERFACE_SERVICE_MANAGER)?;
+            self.0.lock().unwrap().push(code);
+            match code {
+                7 => {
+                    reply.put_i32(ExceptionCode::HasReplyHeader as i32)?;
+                    reply.put_i32(8)?; // header size
+                    reply.put_i32(0)?;
+                    reply.put_bool(true)
+                }
+                9 => {
+                    reply.put_i32(ExceptionCode::None as i32)?;
+                    reply.put_nullable_str16(Some("com.android.apex"))
+                }
+                _ => {
+                    reply.put_i32(ExceptionCode::ServiceSpecific as i32)?;
+                    reply.put_str16("unsupported")?;
+                    reply.put_i32(0)?; // stack trace header
+                    reply.put_i32(5)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn aidl_flavors_send_their_codes() {
+        for &flavor in &[ServiceManagerFlavor::Android11, ServiceManagerFlavor::Android12, ServiceManagerFlavor::Android13] {
+            let driver = MockDriver::new();
+            let recorder = Arc::new(Recorder::default());
+            let _manager = context_manager(&driver, recorder.clone());
+            let binder = Arc::new(Binder::with_transport(Box::new(driver.open())).unwrap());
+            let sm = ServiceManager::with_flavor(binder, flavor);
+
+            assert!(sm.is_declared("android.hardware.light.ILights/default").unwrap());
+            if flavor == ServiceManagerFlavor::Android11 {
+                // Not supported, nothing is sent.
+                let e = sm.get_service_debug_info().unwrap_err();
+                assert_eq!(e.status(), StatusCode::InvalidOperation as i32);
+                let e = sm.updatable_via_apex("x").unwrap_err();
+                assert_eq!(e.status(), StatusCode::InvalidOperation as i32);
+            } else {
+                match *sm.get_service_debug_info().unwrap_err().kind() {
+                    ErrorKind::ServiceSpecific(5, ref message) => assert_eq!(message, "unsupported"),
+                    ref e => panic!("Unexpected {:?}", e),
+                }
+                assert_eq!(sm.updatable_via_apex("x").unwrap().as_deref(), Some("com.android.apex"));
+            }
+            let expected: Vec<u32> = [Method::IsDeclared, Method::GetServiceDebugInfo, Method::UpdatableViaApex]
+                .iter()
+                .filter_map(|m| flavor.code(*m))
+                .collect();
+            assert_eq!(*recorder.0.lock().unwrap(), expected, "{:?}", flavor);
+        }
     }
 }
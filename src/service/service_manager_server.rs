@@ -0,0 +1,331 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use binder::Binder;
+use errors::*;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+use super::policy::{Access, Policy};
+use super::{DeathRecipient, LocalBinder, Object, Parcel, RemoteBinder, DUMP_FLAG_PRIORITY_ALL};
+use super::service_manager::{INTERFACE_SERVICE_MANAGER, SVC_MGR_ADD_SERVICE, SVC_MGR_CHECK_SERVICE,
+                             SVC_MGR_GET_SERVICE, SVC_MGR_LIST_SERVICES};
+use types::{BinderPid, BinderUid};
+
+const AID_USER: BinderUid = 100_000;
+const AID_ISOLATED_START: BinderUid = 99_000;
+const AID_ISOLATED_END: BinderUid = 99_999;
+
+struct Entry {
+    binder: RemoteBinder,
+    allow_isolated: bool,
+    dump_priority: i32,
+}
+
+type Services = Mutex<BTreeMap<String, Entry>>;
+
+/// A context manager speaking the protocol of the legacy servicemanager.
+///
+/// Services are listed in the order of their names and dropped when the
+/// process hosting them dies. Callers are checked against an optional
+/// `Policy`, without one every request is allowed.
+pub struct ServiceManagerServer {
+    binder: Weak<Binder>,
+    policy: Option<Policy>,
+    services: Arc<Services>,
+    obituary: Arc<dyn DeathRecipient>,
+}
+
+/// Removes the services of a dead process.
+struct Obituary {
+    services: Weak<Services>,
+}
+
+impl DeathRecipient for Obituary {
+    fn binder_died(&self, who: &RemoteBinder) {
+        if let Some(services) = self.services.upgrade() {
+            let mut services = services.lock().unwrap();
+            let dead: Vec<String> = services
+                .iter()
+                .filter(|&(_, e)| e.binder == *who)
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in dead {
+                info!("Service {} died", name);
+                services.remove(&name);
+            }
+        }
+    }
+}
+
+impl ServiceManagerServer {
+    /// Become the context manager of `binder`. Incoming transactions are
+    /// served once the binder loops, e.g with `serve`.
+    pub fn start(binder: &Arc<Binder>) -> Result<Arc<ServiceManagerServer>> {
+        ServiceManagerServer::start_with_policy(binder, None)
+    }
+
+    /// Like `start` but deny requests not allowed by `policy`.
+    pub fn start_with_policy(binder: &Arc<Binder>, policy: Option<Policy>) -> Result<Arc<ServiceManagerServer>> {
+        let services = Arc::new(Mutex::new(BTreeMap::new()));
+        let server = Arc::new(ServiceManagerServer {
+            binder: Arc::downgrade(binder),
+            policy,
+            obituary: Arc::new(Obituary { services: Arc::downgrade(&services) }),
+            services,
+        });
+        binder.become_context_manager(server.clone())?;
+        Ok(server)
+    }
+
+    /// The names of the registered services.
+    pub fn services(&self) -> Vec<String> {
+        self.services.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn binder(&self) -> Result<Arc<Binder>> {
+        self.binder.upgrade().ok_or_else(
+            || StatusCode::DeadObject.into(),
+        )
+    }
+
+    /// The pid and uid of the caller.
+    fn caller(&self) -> Result<(BinderPid, BinderUid)> {
+        let binder = self.binder()?;
+        Ok((binder.calling_pid(), binder.calling_uid()))
+    }
+
+    /// Fail with `StatusCode::PermissionDenied` if the policy doesn't allow the caller to `access` `name`.
+    fn check(&self, access: Access, name: &str) -> Result<()> {
+        let (pid, uid) = self.caller()?;
+        match self.policy {
+            Some(ref policy) if !policy.allows(access, name, uid) => {
+                warn!("Denied {:?} of {} to uid {} pid {}", access, name, uid, pid);
+                Err(StatusCode::PermissionDenied.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn get_service(&self, name: &str, reply: &mut Parcel) -> Result<()> {
+        self.check(Access::Find, name)?;
+        let (_, uid) = self.caller()?;
+        let handle = match self.services.lock().unwrap().get(name) {
+            Some(entry) => {
+                let appid = uid % AID_USER;
+                if !entry.allow_isolated && (AID_ISOLATED_START..=AID_ISOLATED_END).contains(&appid) {
+                    warn!("Hiding {} from isolated uid {}", name, uid);
+                    return Err(StatusCode::NameNotFound.into());
+                }
+                entry.binder.handle()
+            }
+            None => {
+                debug!("Service {} not found", name);
+                return Err(StatusCode::NameNotFound.into());
+            }
+        };
+        reply.put_handle(handle)
+    }
+
+    fn add_service(&self, name: &str, handle: u32, allow_isolated: bool, dump_priority: i32) -> Result<()> {
+        if name.is_empty() || name.len() > 127 {
+            return Err(StatusCode::BadValue.into());
+        }
+        self.check(Access::Add, name)?;
+        let binder = self.binder()?;
+        let remote = RemoteBinder::new(&binder, handle)?;
+        remote.link_to_death(self.obituary.clone())?;
+
+        info!("Adding service {} with handle {}", name, handle);
+        let entry = Entry {
+            binder: remote,
+            allow_isolated,
+            dump_priority,
+        };
+        let replaced = self.services.lock().unwrap().insert(name.to_owned(), entry);
+        if let Some(replaced) = replaced {
+            replaced.binder.unlink_to_death(&self.obituary).ok();
+        }
+        Ok(())
+    }
+
+    /// The name of the service at `index` of the ones with any of the
+    /// `dump_priority` flags the caller may see.
+    fn list_service(&self, index: usize, dump_priority: i32) -> Result<String> {
+        let (pid, uid) = self.caller()?;
+        if let Some(ref policy) = self.policy {
+            if !policy.allows_any(Access::List, uid) {
+                warn!("Denied listing services to uid {} pid {}", uid, pid);
+                return Err(StatusCode::PermissionDenied.into());
+            }
+        }
+        let visible = |name: &str| self.policy.as_ref().is_none_or(|p| p.allows(Access::List, name, uid));
+        self.services
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(name, e)| e.dump_priority & dump_priority != 0 && visible(name))
+            .nth(index)
+            .map(|(name, _)| name.clone())
+            .ok_or_else(|| StatusCode::NameNotFound.into())
+    }
+}
+
+impl LocalBinder for ServiceManagerServer {
+    fn on_transact(&self, code: u32, data: &mut Parcel, reply: &mut Parcel, _flags: u32) -> Result<()> {
+        data.get_i32()?; // strict mode
+        let interface = data.get_str16()?;
+        if interface != INTERFACE_SERVICE_MANAGER {
+            warn!("Invalid interface {}", interface);
+            return Err(StatusCode::BadType.into());
+        }
+
+        match code {
+            SVC_MGR_GET_SERVICE | SVC_MGR_CHECK_SERVICE => {
+                let name = data.get_str16()?;
+                self.get_service(&name, reply)
+            }
+            SVC_MGR_ADD_SERVICE => {
+                let name = data.get_str16()?;
+                let handle = match data.get_obj()? {
+                    Object::Handle(handle) => handle,
+                    Object::Binder(_) | Object::Local(_) => {
+                        warn!("Refusing to add local object as {}", name);
+                        return Err(StatusCode::BadType.into());
+                    }
+                };
+                let allow_isolated = data.get_i32()? != 0;
+                let dump_priority = data.get_i32()?;
+                self.add_service(&name, handle, allow_isolated, dump_priority)?;
+                reply.put_i32(0)
+            }
+            SVC_MGR_LIST_SERVICES => {
+                let index = data.get_i32()?;
+                // Older clients don't send a filter.
+                let dump_priority = if data.data_avail() >= 4 { data.get_i32()? } else { DUMP_FLAG_PRIORITY_ALL };
+                let name = self.list_service(index as usize, dump_priority)?;
+                reply.put_str16(&name)
+            }
+            _ => {
+                warn!("Unknown service manager transaction {}", code);
+                Err(StatusCode::UnknownTransaction.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use binder::binder::{Binder, CallResult, Reply};
+    use binder::mock::MockDriver;
+    use binder::tests::{eventually, server, Echo};
+    use errors::*;
+    use service::{Parcel, ServiceManager};
+    use std::sync::Arc;
+    use super::{ServiceManagerServer, INTERFACE_SERVICE_MANAGER, SVC_MGR_LIST_SERVICES};
+    use types::BinderUid;
+
+    fn start(driver: &MockDriver) -> Arc<ServiceManagerServer> {
+        let binder = Arc::new(Binder::with_transport(Box::new(driver.open())).unwrap());
+        let server = ServiceManagerServer::start(&binder).unwrap();
+        Binder::start_thread_pool(&binder).unwrap();
+        server
+    }
+
+    fn client(driver: &MockDriver, uid: BinderUid) -> ServiceManager {
+        ServiceManager::with_binder(Arc::new(Binder::with_transport(Box::new(driver.open_as(uid))).unwrap()))
+    }
+
+    #[test]
+    fn services_are_added_found_and_listed() {
+        let driver = MockDriver::new();
+        let manager = start(&driver);
+        let host = ServiceManager::with_binder(server(&driver));
+        host.add_service("echo", Arc::new(Echo), false, 8).unwrap();
+        host.add_service("alpha", Arc::new(Echo), false, 1).unwrap();
+        assert_eq!(manager.services(), ["alpha", "echo"]);
+
+        let client = client(&driver, 0);
+        assert_eq!(client.list_services().unwrap(), ["alpha", "echo"]);
+        assert_eq!(client.list_services_with_priority(8).unwrap(), ["echo"]);
+        assert!(client.list_services_with_priority(6).unwrap().is_empty());
+
+        // Older clients don't send a filter and see all services.
+        let mut parcel = Parcel::default();
+        parcel.put_i32(0).unwrap();
+        parcel.put_str16(INTERFACE_SERVICE_MANAGER).unwrap();
+        parcel.put_i32(1).unwrap();
+        match client.binder().call(&parcel, 0, SVC_MGR_LIST_SERVICES, 0).unwrap() {
+            CallResult::Reply(Reply::Data(mut reply)) => assert_eq!(reply.get_str16().unwrap(), "echo"),
+            _ => panic!("Unexpected reply"),
+        }
+
+        let mut parcel = Parcel::default();
+        parcel.put_i32(21).unwrap();
+        let echo = client.get_service("echo").unwrap();
+        assert_eq!(echo.call(1, &parcel, 0).unwrap().get_i32().unwrap(), 42);
+        assert_eq!(client.get_service("nope").err().unwrap().status(), StatusCode::NameNotFound as i32);
+    }
+
+    #[test]
+    fn invalid_names_are_rejected() {
+        let driver = MockDriver::new();
+        let manager = start(&driver);
+        let host = ServiceManager::with_binder(server(&driver));
+        for name in &["".to_owned(), "x".repeat(128)] {
+            let e = host.add_service(name, Arc::new(Echo), false, 8).unwrap_err();
+            assert_eq!(e.status(), StatusCode::BadValue as i32);
+        }
+        host.add_service(&"x".repeat(127), Arc::new(Echo), false, 8).unwrap();
+        assert_eq!(manager.services().len(), 1);
+    }
+
+    #[test]
+    fn services_are_hidden_from_isolated_callers() {
+        let driver = MockDriver::new();
+        let _manager = start(&driver);
+        let host = ServiceManager::with_binder(server(&driver));
+        host.add_service("hidden", Arc::new(Echo), false, 8).unwrap();
+        host.add_service("shared", Arc::new(Echo), true, 8).unwrap();
+
+        // Isolated app ids of the first and the tenth user.
+        for &uid in &[99_000, 1_099_999] {
+            let client = client(&driver, uid);
+            assert_eq!(client.get_service("hidden").err().unwrap().status(), StatusCode::NameNotFound as i32);
+            assert!(client.get_service("shared").is_ok());
+        }
+        assert!(client(&driver, 98_999).get_service("hidden").is_ok());
+    }
+
+    #[test]
+    fn services_of_dead_processes_are_removed() {
+        let driver = MockDriver::new();
+        let manager = start(&driver);
+        let host = ServiceManager::with_binder(server(&driver));
+        host.add_service("echo", Arc::new(Echo), false, 8).unwrap();
+        {
+            // Without a thread pool the process dies with its binder.
+            let dying = client(&driver, 0);
+            dying.add_service("dying", Arc::new(Echo), false, 8).unwrap();
+            // Replacing a service of a living process.
+            dying.add_service("echo", Arc::new(Echo), false, 8).unwrap();
+        }
+        assert!(eventually(|| manager.services().is_empty()));
+
+        let client = client(&driver, 0);
+        assert!(client.get_service("dying").is_err());
+        host.add_service("echo", Arc::new(Echo), false, 8).unwrap();
+        assert_eq!(client.list_services().unwrap(), ["echo"]);
+    }
+}
@@ -0,0 +1,176 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use binder::Binder;
+use errors::*;
+use std::sync::{Arc, Weak};
+use super::{LocalBinder, Object, Parcel, RemoteBinder, Service, ServiceManager};
+
+const INTERFACE_SERVICE_CALLBACK: &str = "android.os.IServiceCallback";
+const ON_REGISTRATION: u32 = 1;
+
+type Callback = Box<dyn Fn(&str, Service) + Send + Sync>;
+
+/// The `IServiceCallback` passed to the service manager.
+pub(crate) struct ServiceCallback {
+    binder: Weak<Binder>,
+    callback: Callback,
+}
+
+impl ServiceCallback {
+    pub(crate) fn new(binder: &Arc<Binder>, callback: Callback) -> ServiceCallback {
+        ServiceCallback {
+            binder: Arc::downgrade(binder),
+            callback,
+        }
+    }
+}
+
+impl LocalBinder for ServiceCallback {
+    fn on_transact(&self, code: u32, data: &mut Parcel, _reply: &mut Parcel, _flags: u32) -> Result<()> {
+        if code != ON_REGISTRATION {
+            return Err(StatusCode::UnknownTransaction.into());
+        }
+        data.enforce_aidl_interface(INTERFACE_SERVICE_CALLBACK)?;
+        let name = data.get_str16()?;
+        match data.get_obj()? {
+            Object::Handle(h) => {
+                let binder = self.binder.upgrade().ok_or_else(|| Error::from(StatusCode::DeadObject))?;
+                info!("Service {} registered with handle {}", name, h);
+                (self.callback)(&name, Service::new(RemoteBinder::new(&binder, h)?));
+            }
+            Object::Binder(_) | Object::Local(_) => warn!("Ignoring registration of local service {}", name),
+        }
+        Ok(())
+    }
+}
+
+/// A callback registered with `ServiceManager::register_for_notifications`.
+/// The callback is unregistered on drop.
+pub struct ServiceNotification {
+    service_manager: ServiceManager,
+    name: String,
+    callback: Arc<ServiceCallback>,
+}
+
+impl ServiceNotification {
+    pub(crate) fn new(service_manager: ServiceManager, name: &str, callback: Arc<ServiceCallback>) -> ServiceNotification {
+        ServiceNotification {
+            service_manager,
+            name: name.to_owned(),
+            callback,
+        }
+    }
+
+    /// The name of the service the callback is registered for.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for ServiceNotification {
+    fn drop(&mut self) {
+        self.service_manager
+            .unregister_for_notifications(&self.name, self.callback.clone())
+            .unwrap_or_else(|e| {
+                warn!("Failed to unregister notifications for {}: {}", self.name, e);
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use binder::Binder;
+    use binder::mock::MockDriver;
+    use binder::tests::{context_manager, i32_parcel, server, Echo};
+    use errors::*;
+    use service::{LocalBinder, Object, Parcel, RemoteBinder, ServiceManager, ServiceManagerFlavor};
+    use std::sync::{Arc, Mutex, Weak};
+    use super::{INTERFACE_SERVICE_CALLBACK, ON_REGISTRATION};
+
+    /// A service manager keeping the last registered callback.
+    #[derive(Default)]
+    struct Manager {
+        binder: Mutex<Weak<Binder>>,
+        callback: Mutex<Option<RemoteBinder>>,
+    }
+
+    impl Manager {
+        /// Announce the registration of `name` as `object`.
+        fn announce(&self, name: &str, object: &Object) {
+            let mut parcel = Parcel::default();
+            parcel.put_aidl_interface_token(INTERFACE_SERVICE_CALLBACK).unwrap();
+            parcel.put_str16(name).unwrap();
+            parcel.put(object).unwrap();
+            let callback = self.callback.lock().unwrap().clone().unwrap();
+            callback.transact(ON_REGISTRATION, &parcel, 0).unwrap();
+        }
+    }
+
+    impl LocalBinder for Manager {
+        fn on_transact(&self, code: u32, data: &mut Parcel, reply: &mut Parcel, _flags: u32) -> Result<()> {
+            data.enforce_aidl_interface("android.os.IServiceManager")?;
+            data.get_str16()?;
+            let callback = match data.get_obj()? {
+                Object::Handle(handle) => {
+                    let binder = self.binder.lock().unwrap().upgrade().unwrap();
+                    RemoteBinder::new(&binder, handle)?
+                }
+                _ => return Err(StatusCode::BadType.into()),
+            };
+            match code {
+                5 => *self.callback.lock().unwrap() = Some(callback),
+                6 => *self.callback.lock().unwrap() = None,
+                _ => return Err(StatusCode::UnknownTransaction.into()),
+            }
+            reply.put_i32(0) // status header
+        }
+    }
+
+    #[test]
+    fn registrations_are_delivered() {
+        let driver = MockDriver::new();
+        let manager = Arc::new(Manager::default());
+        let binder = context_manager(&driver, manager.clone());
+        *manager.binder.lock().unwrap() = Arc::downgrade(&binder);
+        let sm = ServiceManager::with_flavor(server(&driver), ServiceManagerFlavor::Android13);
+
+        let services = Arc::new(Mutex::new(vec![]));
+        let received = services.clone();
+        let notification = sm
+            .register_for_notifications("echo", move |name, service| {
+                received.lock().unwrap().push((name.to_owned(), service));
+            })
+            .unwrap();
+        assert_eq!(notification.name(), "echo");
+
+        manager.announce("echo", &Object::Local(Arc::new(Echo)));
+        {
+            let services = services.lock().unwrap();
+            assert_eq!(services.len(), 1);
+            assert_eq!(services[0].0, "echo");
+            let mut reply = services[0].1.call(1, &i32_parcel(21), 0).unwrap();
+            assert_eq!(reply.get_i32().unwrap(), 42);
+        }
+
+        // The callback itself is a service of the notified process.
+        let callback = manager.callback.lock().unwrap().clone().unwrap();
+        manager.announce("local", &Object::Handle(callback.handle()));
+        assert_eq!(services.lock().unwrap().len(), 1);
+
+        drop(notification);
+        assert!(manager.callback.lock().unwrap().is_none());
+    }
+}
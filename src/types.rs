@@ -16,19 +16,76 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use errors::*;
+use std::io;
 use std::mem::transmute;
 
-#[cfg(feature = "binder_version_7")]
-pub type BinderSize = u32;
-#[cfg(feature = "binder_version_8")]
+/// `binder_size_t`. Held as 64 bit and narrowed for protocol version 7.
 pub type BinderSize = u64;
-#[cfg(feature = "binder_version_7")]
-pub type BinderPtr = u32;
-#[cfg(feature = "binder_version_8")]
+/// `binder_uintptr_t`. Held as 64 bit and narrowed for protocol version 7.
 pub type BinderPtr = u64;
 pub type BinderPid = i32;
 pub type BinderUid = u32;
 
+/// The protocol version reported by `BINDER_VERSION`.
+///
+/// Version 7 is spoken by 32 bit kernels and uses 32 bit pointers and sizes
+/// in all driver structures, version 8 uses 64 bit ones. The structures in
+/// this module are kept in the version 8 layout and encoded for the version
+/// in use when talking to the driver.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    V7 = 7,
+    #[default]
+    V8 = 8,
+}
+
+impl Protocol {
+    pub fn from_version(version: i32) -> Result<Protocol> {
+        match version {
+            7 => Ok(Protocol::V7),
+            8 => Ok(Protocol::V8),
+            v => Err(format!("Unsupported binder protocol version {}", v).into()),
+        }
+    }
+
+    pub fn version(&self) -> i32 {
+        *self as i32
+    }
+
+    /// Size of `binder_uintptr_t` and `binder_size_t`.
+    pub fn word_size(&self) -> usize {
+        match *self {
+            Protocol::V7 => 4,
+            Protocol::V8 => 8,
+        }
+    }
+
+    pub fn write_word(&self, out: &mut Vec<u8>, v: u64) {
+        match *self {
+            Protocol::V7 => out.write_u32::<LittleEndian>(v as u32).unwrap(),
+            Protocol::V8 => out.write_u64::<LittleEndian>(v).unwrap(),
+        }
+    }
+
+    pub fn read_word(&self, d: &mut &[u8]) -> io::Result<u64> {
+        match *self {
+            Protocol::V7 => d.read_u32::<LittleEndian>().map(u64::from),
+            Protocol::V8 => d.read_u64::<LittleEndian>(),
+        }
+    }
+}
+
+const IOC_NONE: u32 = 0;
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+/// Encode an ioctl like request code the way `_IOC` does.
+fn ioc(dir: u32, type_: u8, nr: u32, size: usize) -> u32 {
+    dir << 30 | (size as u32) << 16 | (type_ as u32) << 8 | nr
+}
+
 #[repr(u32)]
 pub enum FlatBinderFlags {
   PriorityMask = 0xff,
@@ -67,7 +124,7 @@ pub struct BinderVersion {
 }
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct BinderWriteRead {
     pub write_size: BinderSize,
     pub write_consumed: BinderSize,
@@ -77,15 +134,27 @@ pub struct BinderWriteRead {
     pub read_buffer: BinderPtr,
 }
 
-impl Default for BinderWriteRead {
-    fn default() -> Self {
-        BinderWriteRead {
-            write_size: 0,
-            write_consumed: 0,
-            write_buffer: 0,
-            read_size: 0,
-            read_consumed: 0,
-            read_buffer: 0,
+/// `BinderWriteRead` in the layout of protocol version 7.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct BinderWriteRead32 {
+    pub write_size: u32,
+    pub write_consumed: u32,
+    pub write_buffer: u32,
+    pub read_size: u32,
+    pub read_consumed: u32,
+    pub read_buffer: u32,
+}
+
+impl From<&BinderWriteRead> for BinderWriteRead32 {
+    fn from(bwr: &BinderWriteRead) -> BinderWriteRead32 {
+        BinderWriteRead32 {
+            write_size: bwr.write_size as u32,
+            write_consumed: bwr.write_consumed as u32,
+            write_buffer: bwr.write_buffer as u32,
+            read_size: bwr.read_size as u32,
+            read_consumed: bwr.read_consumed as u32,
+            read_buffer: bwr.read_buffer as u32,
         }
     }
 }
@@ -102,6 +171,46 @@ pub struct BinderTransactionData {
     pub data_size: BinderSize,
     pub offsets_size: BinderSize,
     pub data: BinderPtr,
+    pub offsets: BinderPtr,
+}
+
+impl BinderTransactionData {
+    /// Encoded size in `protocol`.
+    pub fn size(protocol: Protocol) -> usize {
+        6 * protocol.word_size() + 4 * 4
+    }
+
+    pub fn encode(&self, protocol: Protocol, out: &mut Vec<u8>) {
+        // The target is a union of the handle and a pointer.
+        protocol.write_word(out, u64::from(self.target));
+        protocol.write_word(out, self.cookie);
+        out.write_u32::<LittleEndian>(self.code).unwrap();
+        out.write_u32::<LittleEndian>(self.flags).unwrap();
+        out.write_i32::<LittleEndian>(self.sender_pid).unwrap();
+        out.write_u32::<LittleEndian>(self.sender_euid).unwrap();
+        protocol.write_word(out, self.data_size);
+        protocol.write_word(out, self.offsets_size);
+        protocol.write_word(out, self.data);
+        protocol.write_word(out, self.offsets);
+    }
+
+    pub fn decode(protocol: Protocol, d: &mut &[u8]) -> Result<BinderTransactionData> {
+        let decode = |d: &mut &[u8]| -> io::Result<BinderTransactionData> {
+            Ok(BinderTransactionData {
+                target: protocol.read_word(d)? as u32,
+                cookie: protocol.read_word(d)?,
+                code: d.read_u32::<LittleEndian>()?,
+                flags: d.read_u32::<LittleEndian>()?,
+                sender_pid: d.read_i32::<LittleEndian>()?,
+                sender_euid: d.read_u32::<LittleEndian>()?,
+                data_size: protocol.read_word(d)?,
+                offsets_size: protocol.read_word(d)?,
+                data: protocol.read_word(d)?,
+                offsets: protocol.read_word(d)?,
+            })
+        };
+        decode(d).chain_err(|| "Transaction data too short")
+    }
 }
 
 #[repr(u32)]
@@ -113,56 +222,148 @@ pub enum TransactionFlags {
     ACCEPT_FDS = 16,
 }
 
-// TODO
+/// Commands written to the driver. The discriminant is the command number,
+/// the code depends on the protocol version since it includes the payload size.
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum BinderDriverCommandProtocol {
-    BC_TRANSACTION = 1076388608,
-    BC_REPLY = 1076388609,
-    BC_ACQUIRE_RESULT = 1074029314,
-    BC_FREE_BUFFER = 1074029315,
-    BC_INCREFS = 1074029316,
-    BC_ACQUIRE = 1074029317,
-    BC_RELEASE = 1074029318,
-    BC_DECREFS = 1074029319,
-    BC_INCREFS_DONE = 1074291464,
-    BC_ACQUIRE_DONE = 1074291465,
-    BC_ATTEMPT_ACQUIRE = 1074291466,
-    BC_REGISTER_LOOPER = 25355,
-    BC_ENTER_LOOPER = 25356,
-    BC_EXIT_LOOPER = 25357,
-    BC_REQUEST_DEATH_NOTIFICATION = 1074291470,
-    BC_CLEAR_DEATH_NOTIFICATION = 1074291471,
-    BC_DEAD_BINDER_DONE = 1074029328,
-}
-
-// TODO
+    BC_TRANSACTION = 0,
+    BC_REPLY = 1,
+    BC_ACQUIRE_RESULT = 2,
+    BC_FREE_BUFFER = 3,
+    BC_INCREFS = 4,
+    BC_ACQUIRE = 5,
+    BC_RELEASE = 6,
+    BC_DECREFS = 7,
+    BC_INCREFS_DONE = 8,
+    BC_ACQUIRE_DONE = 9,
+    BC_ATTEMPT_ACQUIRE = 10,
+    BC_REGISTER_LOOPER = 11,
+    BC_ENTER_LOOPER = 12,
+    BC_EXIT_LOOPER = 13,
+    BC_REQUEST_DEATH_NOTIFICATION = 14,
+    BC_CLEAR_DEATH_NOTIFICATION = 15,
+    BC_DEAD_BINDER_DONE = 16,
+}
+
+impl BinderDriverCommandProtocol {
+    pub fn from_code(code: u32, protocol: Protocol) -> Option<Self> {
+        use self::BinderDriverCommandProtocol::*;
+        [
+            BC_TRANSACTION,
+            BC_REPLY,
+            BC_ACQUIRE_RESULT,
+            BC_FREE_BUFFER,
+            BC_INCREFS,
+            BC_ACQUIRE,
+            BC_RELEASE,
+            BC_DECREFS,
+            BC_INCREFS_DONE,
+            BC_ACQUIRE_DONE,
+            BC_ATTEMPT_ACQUIRE,
+            BC_REGISTER_LOOPER,
+            BC_ENTER_LOOPER,
+            BC_EXIT_LOOPER,
+            BC_REQUEST_DEATH_NOTIFICATION,
+            BC_CLEAR_DEATH_NOTIFICATION,
+            BC_DEAD_BINDER_DONE,
+        ].iter()
+            .cloned()
+            .find(|c| c.code(protocol) == code)
+    }
+
+    pub fn code(&self, protocol: Protocol) -> u32 {
+        let size = self.payload_size(protocol);
+        let dir = if size == 0 { IOC_NONE } else { IOC_WRITE };
+        ioc(dir, b'c', *self as u32, size)
+    }
+
+    /// Size of the data following the command in the write buffer.
+    pub fn payload_size(&self, protocol: Protocol) -> usize {
+        use self::BinderDriverCommandProtocol::*;
+        let word = protocol.word_size();
+        match *self {
+            BC_TRANSACTION | BC_REPLY => BinderTransactionData::size(protocol),
+            BC_ACQUIRE_RESULT | BC_INCREFS | BC_ACQUIRE | BC_RELEASE | BC_DECREFS => 4,
+            BC_FREE_BUFFER | BC_DEAD_BINDER_DONE => word,
+            BC_INCREFS_DONE | BC_ACQUIRE_DONE => 2 * word,
+            BC_ATTEMPT_ACQUIRE => 8,
+            BC_REQUEST_DEATH_NOTIFICATION | BC_CLEAR_DEATH_NOTIFICATION => 4 + word,
+            BC_REGISTER_LOOPER | BC_ENTER_LOOPER | BC_EXIT_LOOPER => 0,
+        }
+    }
+}
+
+/// Commands read from the driver. See `BinderDriverCommandProtocol`.
 #[repr(u32)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum BinderDriverReturnProtocol {
-    BR_ERROR = 2147774976,
-    BR_OK = 29185,
-    BR_TRANSACTION = 2150134274,
-    BR_REPLY = 2150134275,
-    BR_ACQUIRE_RESULT = 2147774980,
-    BR_DEAD_REPLY = 29189,
-    BR_TRANSACTION_COMPLETE = 29190,
-    BR_INCREFS = 2148037127,
-    BR_ACQUIRE = 2148037128,
-    BR_RELEASE = 2148037129,
-    BR_DECREFS = 2148037130,
-    BR_ATTEMPT_ACQUIRE = 2148299275,
-    BR_NOOP = 29196,
-    BR_SPAWN_LOOPER = 29197,
-    BR_FINISHED = 29198,
-    BR_DEAD_BINDER = 2147774991,
-    BR_CLEAR_DEATH_NOTIFICATION_DONE = 2147774992,
-    BR_FAILED_REPLY = 29201,
-}
-
-impl From<u32> for BinderDriverReturnProtocol {
-    fn from(v: u32) -> Self {
-        unsafe { ::std::mem::transmute(v) }
+    BR_ERROR = 0,
+    BR_OK = 1,
+    BR_TRANSACTION = 2,
+    BR_REPLY = 3,
+    BR_ACQUIRE_RESULT = 4,
+    BR_DEAD_REPLY = 5,
+    BR_TRANSACTION_COMPLETE = 6,
+    BR_INCREFS = 7,
+    BR_ACQUIRE = 8,
+    BR_RELEASE = 9,
+    BR_DECREFS = 10,
+    BR_ATTEMPT_ACQUIRE = 11,
+    BR_NOOP = 12,
+    BR_SPAWN_LOOPER = 13,
+    BR_FINISHED = 14,
+    BR_DEAD_BINDER = 15,
+    BR_CLEAR_DEATH_NOTIFICATION_DONE = 16,
+    BR_FAILED_REPLY = 17,
+}
+
+impl BinderDriverReturnProtocol {
+    pub fn from_code(code: u32, protocol: Protocol) -> Option<Self> {
+        use self::BinderDriverReturnProtocol::*;
+        [
+            BR_ERROR,
+            BR_OK,
+            BR_TRANSACTION,
+            BR_REPLY,
+            BR_ACQUIRE_RESULT,
+            BR_DEAD_REPLY,
+            BR_TRANSACTION_COMPLETE,
+            BR_INCREFS,
+            BR_ACQUIRE,
+            BR_RELEASE,
+            BR_DECREFS,
+            BR_ATTEMPT_ACQUIRE,
+            BR_NOOP,
+            BR_SPAWN_LOOPER,
+            BR_FINISHED,
+            BR_DEAD_BINDER,
+            BR_CLEAR_DEATH_NOTIFICATION_DONE,
+            BR_FAILED_REPLY,
+        ].iter()
+            .cloned()
+            .find(|c| c.code(protocol) == code)
+    }
+
+    pub fn code(&self, protocol: Protocol) -> u32 {
+        let size = self.payload_size(protocol);
+        let dir = if size == 0 { IOC_NONE } else { IOC_READ };
+        ioc(dir, b'r', *self as u32, size)
+    }
+
+    /// Size of the data following the command in the read buffer.
+    pub fn payload_size(&self, protocol: Protocol) -> usize {
+        use self::BinderDriverReturnProtocol::*;
+        let word = protocol.word_size();
+        match *self {
+            BR_ERROR | BR_ACQUIRE_RESULT => 4,
+            BR_TRANSACTION | BR_REPLY => BinderTransactionData::size(protocol),
+            BR_INCREFS | BR_ACQUIRE | BR_RELEASE | BR_DECREFS => 2 * word,
+            // The priority is padded to a word.
+            BR_ATTEMPT_ACQUIRE => 3 * word,
+            BR_DEAD_BINDER | BR_CLEAR_DEATH_NOTIFICATION_DONE => word,
+            _ => 0,
+        }
     }
 }
 
@@ -184,6 +385,169 @@ pub const FLAG_ONEWAY: Transaction = Transaction::FirstCall;
 pub struct FlatBinderObject {
     pub type_: u32,
     pub flags: u32,
-    pub handle_binder: BinderPtr, // TODO
+    /// Union of the binder pointer and the handle.
+    pub handle_binder: BinderPtr,
     pub cookie: BinderPtr,
 }
+
+impl FlatBinderObject {
+    /// Encoded size in `protocol`.
+    pub fn size(protocol: Protocol) -> usize {
+        2 * 4 + 2 * protocol.word_size()
+    }
+
+    pub fn encode(&self, protocol: Protocol, out: &mut Vec<u8>) {
+        out.write_u32::<LittleEndian>(self.type_).unwrap();
+        out.write_u32::<LittleEndian>(self.flags).unwrap();
+        protocol.write_word(out, self.handle_binder);
+        protocol.write_word(out, self.cookie);
+    }
+
+    pub fn decode(protocol: Protocol, d: &mut &[u8]) -> Result<FlatBinderObject> {
+        let decode = |d: &mut &[u8]| -> io::Result<FlatBinderObject> {
+            Ok(FlatBinderObject {
+                type_: d.read_u32::<LittleEndian>()?,
+                flags: d.read_u32::<LittleEndian>()?,
+                handle_binder: protocol.read_word(d)?,
+                cookie: protocol.read_word(d)?,
+            })
+        };
+        decode(d).chain_err(|| "Flat binder object too short")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use service::{Object, Parcel};
+    use std::mem::size_of;
+    use super::*;
+
+    fn transaction() -> BinderTransactionData {
+        BinderTransactionData {
+            target: 3,
+            cookie: 0x1122_3344,
+            code: 7,
+            flags: TransactionFlags::ONE_WAY as u32,
+            sender_pid: 42,
+            sender_euid: 1000,
+            data_size: 16,
+            offsets_size: 8,
+            data: 0x1000,
+            offsets: 0x2000,
+        }
+    }
+
+    #[test]
+    fn write_read_layouts() {
+        assert_eq!(size_of::<BinderWriteRead>(), 48);
+        assert_eq!(size_of::<BinderWriteRead32>(), 24);
+        let bwr = BinderWriteRead {
+            write_size: 1,
+            write_consumed: 2,
+            write_buffer: 3,
+            read_size: 4,
+            read_consumed: 5,
+            read_buffer: 6,
+        };
+        let bwr32 = BinderWriteRead32::from(&bwr);
+        assert_eq!(
+            [bwr32.write_size, bwr32.write_consumed, bwr32.write_buffer, bwr32.read_size, bwr32.read_consumed, bwr32.read_buffer],
+            [1, 2, 3, 4, 5, 6]
+        );
+    }
+
+    #[test]
+    fn transaction_data_layouts() {
+        assert_eq!(BinderTransactionData::size(Protocol::V7), 40);
+        assert_eq!(BinderTransactionData::size(Protocol::V8), 64);
+        for &protocol in &[Protocol::V7, Protocol::V8] {
+            let mut out = vec![];
+            transaction().encode(protocol, &mut out);
+            assert_eq!(out.len(), BinderTransactionData::size(protocol));
+            // The code follows the target and cookie words.
+            let word = protocol.word_size();
+            assert_eq!(out[2 * word], 7);
+
+            let mut d = &out[..];
+            let t = BinderTransactionData::decode(protocol, &mut d).unwrap();
+            assert!(d.is_empty());
+            assert_eq!((t.target, t.cookie, t.code, t.flags), (3, 0x1122_3344, 7, 1));
+            assert_eq!((t.sender_pid, t.sender_euid), (42, 1000));
+            assert_eq!((t.data_size, t.offsets_size, t.data, t.offsets), (16, 8, 0x1000, 0x2000));
+
+            let mut d = &out[..out.len() - 1];
+            assert!(BinderTransactionData::decode(protocol, &mut d).is_err());
+        }
+    }
+
+    #[test]
+    fn flat_binder_object_layouts() {
+        let object = FlatBinderObject {
+            type_: BinderType::Handle as u32,
+            flags: FlatBinderFlags::AcceptFds as u32,
+            handle_binder: 5,
+            cookie: 0,
+        };
+        let mut v7 = vec![];
+        object.encode(Protocol::V7, &mut v7);
+        assert_eq!(v7, [0x85, b'*', b'h', b's', 0, 1, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0]);
+        let mut v8 = vec![];
+        object.encode(Protocol::V8, &mut v8);
+        assert_eq!(v8.len(), FlatBinderObject::size(Protocol::V8));
+        assert_eq!(&v8[..12], &v7[..12]);
+        assert!(v8[12..].iter().all(|&b| b == 0));
+
+        for &(protocol, ref encoded) in &[(Protocol::V7, v7), (Protocol::V8, v8)] {
+            let mut d = &encoded[..];
+            let decoded = FlatBinderObject::decode(protocol, &mut d).unwrap();
+            assert!(d.is_empty());
+            assert_eq!((decoded.type_, decoded.flags), (object.type_, object.flags));
+            assert_eq!((decoded.handle_binder, decoded.cookie), (5, 0));
+            let mut d = &encoded[..encoded.len() - 1];
+            assert!(FlatBinderObject::decode(protocol, &mut d).is_err());
+        }
+    }
+
+    #[test]
+    fn command_codes() {
+        use self::BinderDriverCommandProtocol::*;
+        use self::BinderDriverReturnProtocol::*;
+        assert_eq!(BC_TRANSACTION.code(Protocol::V7), 0x4028_6300);
+        assert_eq!(BC_TRANSACTION.code(Protocol::V8), 0x4040_6300);
+        assert_eq!(BC_REQUEST_DEATH_NOTIFICATION.code(Protocol::V7), 0x4008_630e);
+        assert_eq!(BC_ENTER_LOOPER.code(Protocol::V8), 0x630c);
+        assert_eq!(BR_TRANSACTION.code(Protocol::V8), 0x8040_7202);
+        assert_eq!(BR_INCREFS.code(Protocol::V7), 0x8008_7207);
+        assert_eq!(BR_INCREFS.code(Protocol::V8), 0x8010_7207);
+        for &protocol in &[Protocol::V7, Protocol::V8] {
+            for &c in &[BC_TRANSACTION, BC_FREE_BUFFER, BC_ENTER_LOOPER] {
+                assert_eq!(BinderDriverCommandProtocol::from_code(c.code(protocol), protocol), Some(c));
+            }
+            for &c in &[BR_REPLY, BR_DEAD_BINDER, BR_NOOP] {
+                assert_eq!(BinderDriverReturnProtocol::from_code(c.code(protocol), protocol), Some(c));
+            }
+        }
+        // The payload size is part of the code.
+        assert_eq!(BinderDriverCommandProtocol::from_code(BC_TRANSACTION.code(Protocol::V8), Protocol::V7), None);
+    }
+
+    #[test]
+    fn parcel_objects_are_converted() {
+        let mut parcel = Parcel::default();
+        parcel.put_i32(1).unwrap();
+        parcel.put_handle(7).unwrap();
+        parcel.put_i32(2).unwrap();
+        let v7 = parcel.to_protocol(Protocol::V7).unwrap();
+        assert_eq!(v7.len(), parcel.len() - 8);
+        assert_eq!(v7.offsets(), parcel.offsets());
+
+        let mut v8 = v7.to_protocol(Protocol::V8).unwrap();
+        assert_eq!(&*v8, &*parcel);
+        assert_eq!(v8.get_i32().unwrap(), 1);
+        match v8.get_obj().unwrap() {
+            Object::Handle(7) => (),
+            _ => panic!("Expected handle 7"),
+        }
+        assert_eq!(v8.get_i32().unwrap(), 2);
+    }
+}
@@ -12,7 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(any(test, feature = "mock"))]
+use errors::*;
 use std::mem::size_of;
+#[cfg(any(test, feature = "mock"))]
+use std::ptr;
 use std::slice::from_raw_parts;
 
 macro_rules! hex {
@@ -29,3 +33,39 @@ macro_rules! hex {
 pub fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     unsafe { from_raw_parts((p as *const T) as *const u8, size_of::<T>()) }
 }
+
+/// Read a `T` from the front of `d` and advance `d` past it.
+#[cfg(any(test, feature = "mock"))]
+pub fn read_struct<T>(d: &mut &[u8]) -> Result<T> {
+    if d.len() < size_of::<T>() {
+        return Err(format!("Data too short: {} vs {}", d.len(), size_of::<T>()).into());
+    }
+    let t = unsafe { ptr::read_unaligned(d.as_ptr() as *const T) };
+    *d = &d[size_of::<T>()..];
+    Ok(t)
+}
+
+/// The value of the system property `name`, if set. There are none off Android.
+#[cfg(target_os = "android")]
+pub fn system_property(name: &str) -> Option<String> {
+    use std::ffi::{CStr, CString};
+    use std::os::raw::{c_char, c_int};
+
+    extern "C" {
+        fn __system_property_get(name: *const c_char, value: *mut c_char) -> c_int;
+    }
+
+    // PROP_VALUE_MAX
+    let mut value = [0 as c_char; 92];
+    let name = CString::new(name).ok()?;
+    if unsafe { __system_property_get(name.as_ptr(), value.as_mut_ptr()) } <= 0 {
+        return None;
+    }
+    let value = unsafe { CStr::from_ptr(value.as_ptr()) };
+    value.to_str().ok().map(str::to_owned)
+}
+
+#[cfg(not(target_os = "android"))]
+pub fn system_property(_name: &str) -> Option<String> {
+    None
+}
@@ -0,0 +1,175 @@
+// Copyright (C) 2017 Felix Obenhuber
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Round trips of `#[derive(Parcelable)]` types.
+
+extern crate android_binder;
+
+use android_binder::errors::*;
+use android_binder::service::{Parcel, Parcelable};
+
+#[derive(Parcelable, Debug, Copy, Clone, PartialEq, Eq)]
+enum Color {
+    Red,
+    Green = 5,
+    Blue,
+}
+
+#[derive(Parcelable, Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(i64)]
+enum Wide {
+    Small = 1,
+    Large = 1 << 40,
+}
+
+#[derive(Parcelable, Debug, Clone, PartialEq)]
+enum Value {
+    Number(i32),
+    Text(String),
+    Color(Color),
+}
+
+/// Holds fields without a `Default`.
+#[derive(Parcelable, Debug, Clone, PartialEq)]
+struct Pixel {
+    color: Color,
+    value: Value,
+    x: i32,
+}
+
+/// `Pixel` as written by a newer writer.
+#[derive(Parcelable, Debug, Clone, PartialEq)]
+struct PixelV2 {
+    color: Color,
+    value: Value,
+    x: i32,
+    #[parcelable(default)]
+    y: i32,
+    #[parcelable(default)]
+    label: Option<String>,
+}
+
+#[derive(Parcelable, Debug, Clone, PartialEq)]
+struct Pair<A, B>(A, B);
+
+#[derive(Parcelable, Debug, Clone, PartialEq)]
+struct Wrapper<T>
+where
+    T: Clone,
+{
+    values: Vec<T>,
+}
+
+fn round_trip<T: Parcelable>(value: &T) -> T {
+    let mut parcel = Parcel::default();
+    parcel.put(value).unwrap();
+    let value = parcel.get().unwrap();
+    assert_eq!(parcel.data_avail(), 0);
+    value
+}
+
+#[test]
+fn enums() {
+    for &color in &[Color::Red, Color::Green, Color::Blue] {
+        assert_eq!(round_trip(&color), color);
+    }
+    let mut parcel = Parcel::default();
+    parcel.put(&Color::Blue).unwrap();
+    assert_eq!(parcel.get_i32().unwrap(), 6);
+
+    parcel.put_i32(1).unwrap();
+    assert_eq!(parcel.get::<Color>().err().unwrap().status(), StatusCode::BadValue as i32);
+}
+
+#[test]
+fn i64_enums() {
+    for &wide in &[Wide::Small, Wide::Large] {
+        assert_eq!(round_trip(&wide), wide);
+    }
+    let mut parcel = Parcel::default();
+    parcel.put(&Wide::Large).unwrap();
+    assert_eq!(parcel.get_i64().unwrap(), 1 << 40);
+    assert_eq!(<Wide as Parcelable>::MIN_SIZE, 8);
+}
+
+#[test]
+fn unions() {
+    for value in &[Value::Number(-3), Value::Text("text".to_owned()), Value::Color(Color::Green)] {
+        assert_eq!(&round_trip(value), value);
+    }
+    let mut parcel = Parcel::default();
+    parcel.put(&Value::Text("a".to_owned())).unwrap();
+    assert_eq!(parcel.get_i32().unwrap(), 1); // non-null
+    assert_eq!(parcel.get_i32().unwrap(), 1); // variant
+    assert_eq!(parcel.get_str16().unwrap(), "a");
+
+    let mut parcel = Parcel::default();
+    parcel.put_i32(1).unwrap();
+    parcel.put_i32(3).unwrap();
+    assert_eq!(parcel.get::<Value>().err().unwrap().status(), StatusCode::BadValue as i32);
+}
+
+#[test]
+fn structs() {
+    let pixel = Pixel {
+        color: Color::Blue,
+        value: Value::Number(1),
+        x: 7,
+    };
+    assert_eq!(round_trip(&pixel), pixel);
+    assert_eq!(round_trip(&Some(pixel.clone())), Some(pixel));
+    assert_eq!(round_trip(&None::<Pixel>), None);
+    assert_eq!(round_trip(&Pair(1u8, "b".to_owned())), Pair(1u8, "b".to_owned()));
+    let wrapper = Wrapper { values: vec![Pair(1i64, Color::Red), Pair(2, Color::Green)] };
+    assert_eq!(round_trip(&wrapper), wrapper);
+}
+
+#[test]
+fn newer_fields_are_skipped() {
+    let mut parcel = Parcel::default();
+    parcel.put(&PixelV2 {
+        color: Color::Green,
+        value: Value::Color(Color::Red),
+        x: 1,
+        y: 2,
+        label: Some("label".to_owned()),
+    }).unwrap();
+    parcel.put_i32(42).unwrap();
+    let pixel: Pixel = parcel.get().unwrap();
+    assert_eq!((pixel.color, pixel.value, pixel.x), (Color::Green, Value::Color(Color::Red), 1));
+    assert_eq!(parcel.get_i32().unwrap(), 42);
+}
+
+#[test]
+fn missing_fields() {
+    let pixel = Pixel {
+        color: Color::Red,
+        value: Value::Number(3),
+        x: 4,
+    };
+    let mut parcel = Parcel::default();
+    parcel.put(&pixel).unwrap();
+    let v2: PixelV2 = parcel.get().unwrap();
+    assert_eq!((v2.color, v2.value, v2.x, v2.y, v2.label), (Color::Red, Value::Number(3), 4, 0, None));
+
+    // Only fields marked #[parcelable(default)] may be missing.
+    let mut parcel = Parcel::default();
+    parcel.put_i32(1).unwrap();
+    parcel.put_sized(|p| {
+        p.put(&Color::Red)?;
+        p.put(&Value::Number(3))
+    }).unwrap();
+    assert_eq!(parcel.get::<Pixel>().err().unwrap().status(), StatusCode::NotEnoughData as i32);
+}